@@ -1,8 +1,8 @@
 //! Symmetric Synchronization of folders on different machines over an untrusted server. 
 //!
 //! On every machine there are two folders, `remote` and `local`. The `remote` folder contains the
-//! encrypted and signed files that can be copied to the untrusted server. The names of the files are hashed
-//! and their sizes are masked by a random amount of bytes. All information about the files are
+//! encrypted and signed files that can be copied to the untrusted server. The names of the files are encrypted
+//! and their sizes are masked by padding. All information about the files are
 //! stored in an encrypted image-file, such that we only update files that have changed. The
 //! `local` folder contains the unencrypted files on which you work normally. Ones you are done you
 //! run `symsync update`.
@@ -18,40 +18,212 @@
 use std::fs::{self, File};
 use std::env;
 use std::path::{Path, PathBuf};
-use std::io::{self, Read, Write};
+use std::io::{self, Read, Write, Seek, SeekFrom};
 use std::hash::{Hash, Hasher};
 use std::fmt;
+use std::cmp::Ordering;
 use std::convert::TryFrom;
 use std::process::Command;
-use std::ffi::{OsString};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use std::time::{UNIX_EPOCH, SystemTime};
 
-use openssl::symm::{encrypt, decrypt, Cipher};
+use openssl::symm::{encrypt, decrypt, encrypt_aead, decrypt_aead, Cipher};
 use openssl::rand::rand_bytes;
+use openssl::memcmp;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkcs5::scrypt;
+use openssl::pkey::Id;
+use openssl::pkey_ctx::PkeyCtx;
+use openssl::md::Md;
 
 use siphasher::sip::SipHasher;
 
-//use sha3::{Sha3_256, Digest};
+use sha3::Sha3_256;
+use hmac::{Hmac, Mac};
 
-use serde_json;
-use toml;
 use serde::{Serialize, Deserialize};
-use serde;
 
-use rand;
 use rand_distr::{Exp, Distribution};
+use rand::Rng;
+
+use base32::Alphabet;
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use zeroize::Zeroize;
 
 
 const L_KEY: usize = 32;        // size of the key
-const L_IV: usize = 16;         // size of the initial vector needed for AES
+const L_IV: usize = 16;         // size of the initial vector needed for AES-CBC
+const L_NONCE: usize = 12;      // size of the nonce needed for AES-GCM
+const L_NONCE_PREFIX: usize = 7; // size of the nonce prefix of a blob in chunks; the rest is counter and last flag
+const L_CHUNK: usize = 1 << 16;  // size of the plaintext of a chunk
+const L_PIECE_MIN: usize = 1 << 14;  // smallest piece of a file, except for the last one
+const L_PIECE_MAX: usize = 1 << 18;  // largest piece of a file
+const PIECE_BITS: u32 = 16;          // a piece ends where this many bits of the rolling hash are zero
+const L_PACK_MIN: u32 = 1 << 20;     // smallest size of a pack, such that every piece fits
+const L_PACK_NAME: usize = 16;       // size of the random name of a pack
+const L_PIECE_NAME: usize = 16;      // size of the keyed hash that names a piece
+const L_TAG: usize = 16;        // size of the authentication tag of AES-GCM
+const L_KEY_ID: usize = 8;      // size of the key id in the header
+const L_MAC: usize = 32;        // size of the HMAC-SHA3-256 of a blob
+const L_SIV: usize = 16;        // size of the synthetic IV of an encrypted name
+const L_KEY_CHECK: usize = 12;  // size of the key check value in the header of the remote image
+const L_CODE_CHECK: usize = 3;  // size of the checksum of an exported key
+const L_NAME_MAX: usize = 255;  // longest file name in `gpath`
+
+const L_SALT: usize = 16;       // size of the salt for the key derivation from a passphrase
+const SCRYPT_MAXMEM: u64 = 1 << 31; // most memory scrypt may use, whatever the header says
+
+const MAGIC: &[u8; 4] = b"SYMS";   // first bytes of every blob and the remote image
+const FORMAT_VERSION: u8 = 1;       // version of the header written by this program
+
+const EXT_KDF: u8 = 1;              // header extension: parameters of the key derivation function
+const EXT_SUBKEY: u8 = 2;           // header extension: purpose and info of the subkey used for encryption
+const EXT_METADATA: u8 = 3;         // header extension: encrypted metadata of the file in a blob
+const EXT_CHUNK: u8 = 4;            // header extension: size of the chunks of a blob in chunks
+const EXT_COMPRESSION: u8 = 5;      // header extension: compression of the message before encryption
+const EXT_PADDING: u8 = 6;          // header extension: padding policy, if not the exponential one
+const EXT_KEY_CHECK: u8 = 7;        // header extension: key check value of the master key, in the remote image
+const KNOWN_EXTENSIONS: [u8; 7] = [EXT_KDF, EXT_SUBKEY, EXT_METADATA, EXT_CHUNK, EXT_COMPRESSION, EXT_PADDING,
+	EXT_KEY_CHECK];
+
+const L_PADDING_LEN: usize = 8;     // size of the length of the padding at its end (3 for the exponential padding)
+
+// extensions of files that are compressed already and are not worth compressing again
+const COMPRESSED_FORMATS: [&str; 28] = ["7z", "avi", "bz2", "docx", "epub", "flac", "gif", "gz", "heic", "jar", "jpeg",
+	"jpg", "m4a", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "png", "pptx", "rar", "webm", "webp", "xlsx", "xz", "zip", "zst"];
+
+static ENV_PASSPHRASE: &str = "SYMSYNC_PASSPHRASE";
 
-type Key = [u8; L_KEY];
 type Iv = [u8; L_IV];
+type Nonce = [u8; L_NONCE];
+type KeyId = [u8; L_KEY_ID];
+
+/// A 256 bit key. Its bytes live on the heap, such that moving a key leaves no copies behind, and
+/// are overwritten with zeros when it is dropped. `Debug` and `Display` only show the key id.
+/// With the feature `mlock` the memory of keys is locked, such that it is not written to swap.
+struct Key(Box<[u8; L_KEY]>);
+
+impl Key {
+	/// a key of zeros, to be filled in
+	fn zero() -> Self {
+		let key = Key(Box::new([0; L_KEY]));
+		key.lock();
+		key
+	}
+
+	fn random() -> Result<Self> {
+		let mut key = Key::zero();
+		rand_bytes(&mut key[..])?;
+		Ok(key)
+	}
+
+	/// Lock the page of the key in memory. This is best effort: if the limit of locked memory is
+	/// reached, the key is used anyway. Pages are not unlocked, as other keys may share them.
+	#[cfg(feature = "mlock")]
+	fn lock(&self) {
+		unsafe {
+			libc::mlock(self.0.as_ptr() as *const libc::c_void, L_KEY);
+		}
+	}
+
+	#[cfg(not(feature = "mlock"))]
+	fn lock(&self) {}
+}
+
+impl From<[u8; L_KEY]> for Key {
+	fn from(mut bytes: [u8; L_KEY]) -> Self {
+		let mut key = Key::zero();
+		key.copy_from_slice(&bytes);
+		bytes.zeroize();
+		key
+	}
+}
+
+impl std::ops::Deref for Key {
+	type Target = [u8];
+	fn deref(&self) -> &[u8] {
+		&self.0[..]
+	}
+}
+
+impl std::ops::DerefMut for Key {
+	fn deref_mut(&mut self) -> &mut [u8] {
+		&mut self.0[..]
+	}
+}
+
+impl Clone for Key {
+	fn clone(&self) -> Self {
+		let mut key = Key::zero();
+		key.copy_from_slice(&self[..]);
+		key
+	}
+}
+
+impl PartialEq for Key {
+	fn eq(&self, other: &Self) -> bool {
+		memcmp::eq(self, other)
+	}
+}
+
+impl Drop for Key {
+	fn drop(&mut self) {
+		self.0.zeroize();
+	}
+}
+
+impl fmt::Debug for Key {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Key({})", self)
+	}
+}
+
+impl fmt::Display for Key {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", slice_to_hex(&key_id(self)))
+	}
+}
+
+/// A secret string like `key_hex` or a passphrase. It is overwritten with zeros when dropped and
+/// not shown by `Debug`.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(transparent)]
+struct SecretString(String);
+
+impl std::ops::Deref for SecretString {
+	type Target = str;
+	fn deref(&self) -> &str {
+		&self.0
+	}
+}
+
+impl Drop for SecretString {
+	fn drop(&mut self) {
+		self.0.zeroize();
+	}
+}
+
+impl fmt::Debug for SecretString {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "<secret>")
+	}
+}
 
 static FOLDER_SYNC: &str = ".sync";
 static IMAGE_LOCAL: &str = "image.json";
 static IMAGE_REMOTE: &str = "image";
+static CONFIG_FILE: &str = "config.toml";
+static REKEY_PENDING: &str = "rekey.json";
+static GENERATION: &str = "generation";
+static QUARANTINE: &str = "quarantine.json";
+static DOWNLOAD: &str = "download.tmp";
+static CONFLICTS: &str = "conflicts.log";
+static REKEY_TMP: &str = "rekey.tmp";
+static DEVICE: &str = "device";
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
@@ -80,6 +252,457 @@ impl std::error::Error for SigError {
     }
 }
 
+/// Cipher that was used to encrypt a blob in `gpath`.
+// the names are stored in the images, so they cannot lose their prefix
+#[allow(clippy::enum_variant_names)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+enum Algorithm {
+	/// aes_256_cbc without authentication. Only used to read blobs written by older versions.
+	Aes256Cbc,
+	/// aes_256_gcm. The blob is a `Header` followed by the ciphertext and the tag.
+	Aes256Gcm,
+	/// aes_256_gcm in chunks (STREAM construction). The blob is a `Header` followed by the chunks,
+	/// each with its own tag, such that large files never have to be in memory at once.
+	Aes256GcmStream,
+}
+
+/// What the signature in `Metadata` is.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+enum SignatureType {
+	/// entries written by older versions: the SipHash of the content for CBC blobs and the AES-GCM
+	/// tag for AES-GCM blobs
+	#[default]
+	Legacy,
+	/// HMAC-SHA3-256 of the whole blob, keyed with a subkey of the master key named in the header
+	HmacSha3,
+}
+
+/// How the names of the blobs in `gpath` are derived from the names of the files.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum Naming {
+	/// SipHash of the path. Used by older versions. The path cannot be recovered from it.
+	#[default]
+	Siphash,
+	/// the path encrypted deterministically (SIV) and base32 encoded. It can be decrypted with the key.
+	Siv,
+}
+
+/// Compression of the files before encryption.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum Compression {
+	/// files are encrypted as they are
+	#[default]
+	None,
+	/// deflate (as in zip and gzip), for pieces that get at least 1/16 smaller
+	Deflate,
+}
+
+impl Compression {
+	/// the value of the compression extension in the header
+	fn id(self) -> u8 {
+		match self {
+			Compression::None => 0,
+			Compression::Deflate => 1,
+		}
+	}
+	fn from_id(id: u8) -> Result<Self> {
+		match id {
+			0 => Ok(Compression::None),
+			1 => Ok(Compression::Deflate),
+			_ => Err(format!("unknown compression {} in header. Please update symsync.", id).into()),
+		}
+	}
+}
+
+/// How the length of a message is hidden before encryption. The padding ends with its length.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum Padding {
+	/// no padding
+	None,
+	/// an exponentially distributed number of bytes with a mean of 200. Used by older versions.
+	#[default]
+	Exponential,
+	/// Padmé: the length is rounded up such that only the highest bits of it are left. At most 12% overhead.
+	Padme,
+	/// the length is rounded up to the next power of two
+	Pow2,
+	/// the length is rounded up to a multiple of the given number of bytes
+	Block(u32),
+}
+
+impl Default for Algorithm {
+	/// entries in images written by older versions have no algorithm and are CBC encrypted
+	fn default() -> Self {
+		Algorithm::Aes256Cbc
+	}
+}
+
+impl Algorithm {
+	/// the cipher id in the header
+	fn id(self) -> u8 {
+		match self {
+			Algorithm::Aes256Cbc => 0,
+			Algorithm::Aes256Gcm => 1,
+			Algorithm::Aes256GcmStream => 2,
+		}
+	}
+	fn from_id(id: u8) -> Result<Self> {
+		match id {
+			1 => Ok(Algorithm::Aes256Gcm),
+			2 => Ok(Algorithm::Aes256GcmStream),
+			_ => Err(format!("unknown cipher id {} in header. Please update symsync.", id).into()),
+		}
+	}
+}
+
+
+/// Purpose of a subkey derived from the master key with HKDF. Every purpose gets an independent key,
+/// such that a problem with one of them (e.g. a reused nonce) does not affect the others.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Purpose {
+	/// encryption of the remote image
+	Image = 1,
+	/// encryption of the blobs. Optionally there is one key per file.
+	Blob = 2,
+	/// key for the namehash
+	Names = 3,
+	/// key for the HMAC of the blobs
+	Mac = 4,
+	/// key for the metadata embedded in the blobs
+	Metadata = 5,
+	/// key for the pieces of the files
+	Piece = 6,
+	/// key for the packs of small blobs
+	Pack = 7,
+	/// encryption of the local image
+	LocalImage = 8,
+	/// key for the key check value
+	KeyCheck = 9,
+}
+
+impl Purpose {
+	fn from_id(id: u8) -> Result<Self> {
+		match id {
+			1 => Ok(Purpose::Image),
+			2 => Ok(Purpose::Blob),
+			3 => Ok(Purpose::Names),
+			4 => Ok(Purpose::Mac),
+			5 => Ok(Purpose::Metadata),
+			6 => Ok(Purpose::Piece),
+			7 => Ok(Purpose::Pack),
+			8 => Ok(Purpose::LocalImage),
+			9 => Ok(Purpose::KeyCheck),
+			_ => Err(format!("unknown subkey purpose {} in header. Please update symsync.", id).into()),
+		}
+	}
+}
+
+
+/// Header in front of every blob in `gpath` and the remote image. 
+///
+/// The layout is
+/// - magic bytes `SYMS`
+/// - format version (1 byte)
+/// - cipher id (1 byte)
+/// - key id (8 bytes), derived from the key by `key_id`
+/// - nonce length (1 byte) and the nonce
+/// - length of the extensions (2 bytes) and the extensions. Each extension is a tag (1 byte), a
+///   length (2 bytes) and the value. 
+///
+/// The whole header is authenticated as associated data. Readers refuse format versions and
+/// extensions they do not know, such that new formats can be rolled out one machine at a time.
+#[derive(Debug, Clone, PartialEq)]
+struct Header {
+	version: u8,
+	algorithm: Algorithm,
+	key_id: KeyId,
+	nonce: Vec<u8>,
+	extensions: Vec<(u8, Vec<u8>)>,
+}
+
+impl Header {
+	fn new(algorithm: Algorithm, key: &Key, nonce: &[u8]) -> Self {
+		Header {
+			version: FORMAT_VERSION,
+			algorithm,
+			key_id: key_id(key),
+			nonce: nonce.to_vec(),
+			extensions: Vec::new(),
+		}
+	}
+
+	/// return the value of extension `tag` if present
+	fn extension(&self, tag: u8) -> Option<&[u8]> {
+		self.extensions.iter().find(|(t, _)| *t == tag).map(|(_, value)| &value[..])
+	}
+
+	/// return the purpose and info of the subkey, if the data was not encrypted with the master key
+	fn subkey(&self) -> Result<Option<(Purpose, &[u8])>> {
+		match self.extension(EXT_SUBKEY) {
+			Some(value) if !value.is_empty() => Ok(Some((Purpose::from_id(value[0])?, &value[1..]))),
+			Some(_) => Err("empty subkey extension in header".into()),
+			None => Ok(None),
+		}
+	}
+
+	/// return the parameters of the key derivation if the key was derived from a passphrase
+	fn kdf(&self) -> Result<Option<KdfParams>> {
+		match self.extension(EXT_KDF) {
+			Some(value) => Ok(Some(KdfParams::from_bytes(value)?)),
+			None => Ok(None),
+		}
+	}
+
+	/// serialize the header
+	fn to_bytes(&self) -> Vec<u8> {
+		let mut ext = Vec::new();
+		for (tag, value) in &self.extensions {
+			ext.push(*tag);
+			ext.extend(&(value.len() as u16).to_be_bytes());
+			ext.extend(value);
+		}
+		let mut v = Vec::with_capacity(MAGIC.len() + 13 + self.nonce.len() + ext.len());
+		v.extend(MAGIC);
+		v.push(self.version);
+		v.push(self.algorithm.id());
+		v.extend(&self.key_id);
+		v.push(self.nonce.len() as u8);
+		v.extend(&self.nonce);
+		v.extend(&(ext.len() as u16).to_be_bytes());
+		v.extend(ext);
+		v
+	}
+
+	/// metadata of the file embedded in the header of a blob
+	fn metadata(&self, keyring: &Keyring) -> Result<Option<EmbeddedMetadata>> {
+		match self.extension(EXT_METADATA) {
+			Some(value) => Ok(Some(serde_json::from_slice(&my_decrypt(value, keyring, b"metadata")?)?)),
+			None => Ok(None),
+		}
+	}
+
+	/// size of the chunks of a blob in chunks
+	fn chunk_size(&self) -> Result<Option<usize>> {
+		match self.extension(EXT_CHUNK) {
+			Some(value) => {
+				let l = u32::from_be_bytes(<[u8; 4]>::try_from(value)?) as usize;
+				if l == 0 || l > 1 << 24 {
+					return Err("invalid chunk size in header".into());
+				}
+				Ok(Some(l))
+			}
+			None => Ok(None),
+		}
+	}
+
+	/// compression of the message behind the header
+	fn compression(&self) -> Result<Compression> {
+		match self.extension(EXT_COMPRESSION) {
+			Some([id]) => Compression::from_id(*id),
+			Some(_) => Err("invalid compression extension in header".into()),
+			None => Ok(Compression::None),
+		}
+	}
+
+	/// padding of the message behind the header
+	fn padding(&self) -> Result<Padding> {
+		match self.extension(EXT_PADDING) {
+			Some(value) => Padding::from_bytes(value),
+			None => Ok(Padding::Exponential),
+		}
+	}
+
+	/// key check value of the master key. Only the remote image has one.
+	fn key_check(&self) -> Result<Option<&[u8]>> {
+		match self.extension(EXT_KEY_CHECK) {
+			Some(value) if value.len() == L_KEY_CHECK => Ok(Some(value)),
+			Some(_) => Err("invalid key check value in header".into()),
+			None => Ok(None),
+		}
+	}
+
+	/// the key of the data behind the header: the key named by the header, or its subkey
+	fn key(&self, keyring: &Keyring) -> Result<Key> {
+		let key = keyring.for_header(self)?;
+		match self.subkey()? {
+			Some((purpose, info)) => Ok(derive_key(key, purpose, info)),
+			None => Ok(key.clone()),
+		}
+	}
+
+	/// read the header at the beginning of `reader`. Return the header and its bytes.
+	fn read_from<R: Read>(reader: &mut R) -> Result<(Self, Vec<u8>)> {
+		let mut buf = vec![0; MAGIC.len() + 2 + L_KEY_ID + 1];
+		reader.read_exact(&mut buf)?;
+		if !buf.starts_with(MAGIC) {
+			return Err("no symsync header found".into());
+		}
+		// the nonce and the length of the extensions
+		let l = buf.len();
+		buf.resize(l + buf[l-1] as usize + 2, 0);
+		reader.read_exact(&mut buf[l..])?;
+		let l = buf.len();
+		buf.resize(l + u16::from_be_bytes([buf[l-2], buf[l-1]]) as usize, 0);
+		reader.read_exact(&mut buf[l..])?;
+		let (header, _) = Header::from_bytes(&buf)?;
+		Ok((header, buf))
+	}
+
+	/// parse the header at the beginning of `buf`. Return the header and its length in bytes.
+	fn from_bytes(buf: &[u8]) -> Result<(Self, usize)> {
+		let mut r = ByteReader { buf, pos: 0 };
+		if r.take(MAGIC.len())? != MAGIC {
+			return Err("no symsync header found".into());
+		}
+		let version = r.take(1)?[0];
+		if version != FORMAT_VERSION {
+			return Err(format!("format version {} of header is not supported. Please update symsync.", version).into());
+		}
+		let algorithm = Algorithm::from_id(r.take(1)?[0])?;
+		let key_id = KeyId::try_from(r.take(L_KEY_ID)?)?;
+		let l = r.take(1)?[0] as usize;
+		let nonce = r.take(l)?.to_vec();
+		let l = r.take_u16()? as usize;
+		let mut ext = ByteReader { buf: r.take(l)?, pos: 0 };
+		let mut extensions = Vec::new();
+		while ext.pos < ext.buf.len() {
+			let tag = ext.take(1)?[0];
+			let l = ext.take_u16()? as usize;
+			extensions.push((tag, ext.take(l)?.to_vec()));
+		}
+		if let Some((tag, _)) = extensions.iter().find(|(tag, _)| !KNOWN_EXTENSIONS.contains(tag)) {
+			return Err(format!("unknown extension {} in header. Please update symsync.", tag).into());
+		}
+		let header = Header { version, algorithm, key_id, nonce, extensions };
+		Ok((header, r.pos))
+	}
+}
+
+/// helper to read a header field by field
+struct ByteReader<'a> {
+	buf: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+	fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+		if self.pos + n > self.buf.len() {
+			return Err("header is truncated".into());
+		}
+		let slice = &self.buf[self.pos..self.pos+n];
+		self.pos += n;
+		Ok(slice)
+	}
+	fn take_u16(&mut self) -> Result<u16> {
+		let b = self.take(2)?;
+		Ok(u16::from_be_bytes([b[0], b[1]]))
+	}
+	fn take_u32(&mut self) -> Result<u32> {
+		let b = self.take(4)?;
+		Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+	}
+}
+
+
+/// Parameters of scrypt to derive the key from a passphrase. They are stored in the header of the
+/// remote image, such that all devices derive the same key from the same passphrase.
+#[derive(Debug, Clone, PartialEq)]
+struct KdfParams {
+	log_n: u8, 				// scrypt cost parameter N = 2^log_n
+	r: u32,					// block size
+	p: u32,					// parallelization
+	salt: [u8; L_SALT],
+}
+
+impl KdfParams {
+	/// scrypt id in the extension
+	const SCRYPT: u8 = 1;
+
+	/// default parameters with a new random salt. This needs 128 MiB of memory.
+	fn new() -> Self {
+		let mut salt = [0; L_SALT];
+		rand_bytes(&mut salt).unwrap();
+		KdfParams { log_n: 17, r: 8, p: 1, salt }
+	}
+
+	fn to_bytes(&self) -> Vec<u8> {
+		let mut v = vec![KdfParams::SCRYPT, self.log_n];
+		v.extend(&self.r.to_be_bytes());
+		v.extend(&self.p.to_be_bytes());
+		v.extend(&self.salt);
+		v
+	}
+
+	fn from_bytes(buf: &[u8]) -> Result<Self> {
+		let mut r = ByteReader { buf, pos: 0 };
+		if r.take(1)?[0] != KdfParams::SCRYPT {
+			return Err("unknown key derivation function in header. Please update symsync.".into());
+		}
+		let log_n = r.take(1)?[0];
+		let block = r.take_u32()?;
+		let p = r.take_u32()?;
+		let mut salt = [0; L_SALT];
+		salt.copy_from_slice(r.take(L_SALT)?);
+		// the header is not authenticated: whoever controls the remote folder could make every device
+		// allocate lots of memory. log_n = 20 with r = 8 needs 1 GiB.
+		if log_n == 0 || log_n > 20 || block == 0 || block > 8 || p == 0 || p > 4 {
+			return Err("unreasonable scrypt parameters in header".into());
+		}
+		Ok(KdfParams { log_n, r: block, p, salt })
+	}
+
+	/// derive the key from a passphrase
+	fn derive(&self, passphrase: &str) -> Result<Key> {
+		let n = 1u64 << self.log_n;
+		let maxmem = (256 * n * u64::from(self.r) * u64::from(self.p)).min(SCRYPT_MAXMEM);
+		let mut key = Key::zero();
+		scrypt(passphrase.as_bytes(), &self.salt, n, u64::from(self.r), u64::from(self.p), maxmem, &mut key[..])?;
+		Ok(key)
+	}
+}
+
+/// Key error. The data was encrypted with a key that is not in the keyring of this device.
+struct KeyIdError {
+	known: Vec<KeyId>, 		// ids of the keys of this device
+	found: KeyId,			// id in the header
+}
+
+impl fmt::Display for KeyIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let known: Vec<String> = self.known.iter().map(|id| slice_to_hex(id)).collect();
+        write!(f, "The data was encrypted with key {}, but the keys of this device are {}.",
+			slice_to_hex(&self.found), known.join(", "))
+    }
+}
+impl fmt::Debug for KeyIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+impl std::error::Error for KeyIdError {}
+
+/// Rollback error. The remote image is older than an image this device has already seen.
+struct RollbackError {
+	seen: u64, 				// highest generation seen by this device
+	found: u64,				// generation of the remote image
+}
+
+impl fmt::Display for RollbackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The remote image has generation {}, but this device has already seen generation {}. \
+			The remote folder was rolled back to an older state. Refusing to continue.", self.found, self.seen)
+    }
+}
+impl fmt::Debug for RollbackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+impl std::error::Error for RollbackError {}
+
 /// The task of the program
 pub enum Goal {
 	/// encrypt and push everything, deleting what might have been in remote folder.
@@ -88,30 +711,135 @@ pub enum Goal {
 	BlindPull,
 	/// syncronizes based on modification time of files. After an initial BlindPush or BlindPull, this is the default task
 	Update,
+	/// re-encrypt everything in the remote folder with a new key
+	Rekey,
+	/// rebuild the remote image from the blobs
+	Recover,
 }
 
 /// User configurations read from .sync/config.toml
+///
+/// The key is either given directly as `key_hex` or derived from a passphrase. `passphrase` tells
+/// where the passphrase comes from: `"prompt"` asks on the terminal, `"env:NAME"` reads the
+/// environment variable NAME (`"env"` reads SYMSYNC_PASSPHRASE) and `"file:PATH"` reads the first
+/// line of the file PATH.
+///
+/// Instead of `key_hex`, several keys can be listed in `[[keyring]]` sections. The one marked
+/// `active` encrypts, the others are only used to decrypt data whose header names them. With a
+/// passphrase, the derived key is the active one and the keyring holds old keys.
 #[derive(Deserialize, Debug)]
 pub struct Config {
-	key_hex: String,
+	#[serde(default)]
+	key_hex: Option<SecretString>,
+	#[serde(default)]
+	passphrase: Option<String>,
+	#[serde(default)]
+	keyring: Vec<KeyringEntry>,
+	#[serde(default = "default_true")]
+	per_file_keys: bool,	// derive a separate key for every file
+	#[serde(default)]
+	naming: Naming,			// naming of the blobs for blindpush
+	#[serde(default)]
+	compression: Compression,	// compression of the files before encryption
+	#[serde(default)]
+	padding: Padding,		// padding of the blobs and the remote image
+	#[serde(default)]
+	pack_size: Option<u32>,	// if given, small blobs are put into packs of this size
+	#[serde(default)]
+	decoys: u32,			// number of empty packs
+	#[serde(default)]
+	local_image_key: Option<String>,	// "master" or "file:PATH" to encrypt the local image
+	#[serde(default)]
+	conflict: ConflictPolicy,	// what to do with a file that changed on both sides
+	#[serde(default)]
+	conflict_rules: Vec<ConflictRule>,	// policies for the files that match a glob
 	#[serde(skip)]
-	key: Key, 				// converted from key_hex
+	keys: Vec<Key>, 		// converted from key_hex or keyring
+	#[serde(skip)]
+	active: Option<usize>,	// index of the active key in keys
 	gpath: PathBuf,
 	command_push: String,
 	command_pull: String,
 }
 
-impl Config {
-	fn hex_to_key(s: &String) -> Result<Key> {
-	let l = s.len()/2;
-	let mut key: Key = [0; L_KEY];
-	for i in 0..l {
-		let b = &s[2*i..2*i+2];
-		key[i] = u8::from_str_radix(b, 16)?;
+fn default_true() -> bool {
+	true
+}
+
+/// How new blobs are written, as chosen in the config file.
+#[derive(Debug, Clone, Copy)]
+struct BlobFormat {
+	per_file_keys: bool,		// derive a key for every blob
+	compression: Compression,	// compression of the pieces
+	padding: Padding,			// padding of the pieces, the lists of pieces and the remote image
+	pack_size: Option<u32>,		// size of the packs, if small blobs are packed
+	decoys: u32,				// number of empty packs
+}
+
+/// A key in the `[[keyring]]` section of the config file.
+#[derive(Deserialize, Debug)]
+struct KeyringEntry {
+	key_hex: SecretString,
+	#[serde(default)]
+	active: bool,
+	#[serde(default)]
+	id: Option<String>,		// if given, it is checked against the id of the key
+}
+
+/// Keys of this device. New data is encrypted with the active key. Data is decrypted with the key
+/// named in its header.
+#[derive(Debug, Clone)]
+struct Keyring {
+	keys: Vec<Key>,
+	active: usize,
+}
+
+impl Keyring {
+	fn single(key: Key) -> Self {
+		Keyring { keys: vec![key], active: 0 }
+	}
+
+	/// the key for encryption
+	fn active(&self) -> &Key {
+		&self.keys[self.active]
+	}
+
+	/// the key with the given id
+	fn get(&self, id: &KeyId) -> Option<&Key> {
+		self.keys.iter().find(|key| key_id(key) == *id)
+	}
+
+	/// all keys, starting with the active one. For data without header, which does not name its key.
+	fn candidates(&self) -> Vec<&Key> {
+		let mut keys = vec![self.active()];
+		keys.extend(self.keys.iter().enumerate().filter(|(i, _)| *i != self.active).map(|(_, key)| key));
+		keys
+	}
+
+	fn ids(&self) -> Vec<KeyId> {
+		self.candidates().into_iter().map(key_id).collect()
+	}
+
+	/// the key with the id from `header`. Throw a KeyIdError if there is none.
+	fn for_header(&self, header: &Header) -> Result<&Key> {
+		self.get(&header.key_id).ok_or_else(|| KeyIdError { known: self.ids(), found: header.key_id }.into())
 	}
-	Ok(key)
 }
 
+impl Config {
+	/// parse a key of exactly 64 hex digits
+	fn hex_to_key(s: &str) -> Result<Key> {
+		if s.len() != 2*L_KEY || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+			return Err(format!("a key has to be {} hex digits, e.g. from 'openssl rand -hex 32'", 2*L_KEY).into());
+		}
+		let mut key = Key::zero();
+		for (i, k) in key.iter_mut().enumerate() {
+			let b = &s[2*i..2*i+2];
+			*k = u8::from_str_radix(b, 16)?;
+		}
+		Ok(key)
+	}
+
 	/// load the configuration from file
 	///
 	/// # Example
@@ -125,16 +853,376 @@ impl Config {
 	/// ```
 	pub fn load(fname: &Path) -> Result<Self> {
 		//let mut f = File::open(&fname)?;
-		let mut f = myopen(&fname)?;
-		let mut config_string = String::new();
-		f.read_to_string(&mut config_string)?;
+		let mut f = myopen(fname)?;
+		let mut config_string = SecretString::default();
+		f.read_to_string(&mut config_string.0)?;
 		let mut config: Config = toml::from_str(&config_string)?;
-		config.key = Config::hex_to_key(&config.key_hex)?;
+		if config.key_hex.is_some() && (config.passphrase.is_some() || !config.keyring.is_empty()) {
+			return Err("config file contains key_hex and also a passphrase or a keyring. Please choose one.".into());
+		}
+		if let Some(key_hex) = &config.key_hex {
+			config.keys.push(Config::hex_to_key(key_hex)?);
+			config.active = Some(0);
+		}
+		for (i, entry) in config.keyring.iter().enumerate() {
+			let key = Config::hex_to_key(&entry.key_hex)?;
+			if let Some(id) = &entry.id {
+				if *id != slice_to_hex(&key_id(&key)) {
+					return Err(format!("key number {} in keyring does not have id {}", i+1, id).into());
+				}
+			}
+			if entry.active {
+				if config.passphrase.is_some() {
+					return Err("config file has both a passphrase and an active key in the keyring. \
+						Remove the passphrase or set active = false.".into());
+				}
+				if config.active.is_some() {
+					return Err("more than one active key in config file".into());
+				}
+				config.active = Some(i);
+			}
+			config.keys.push(key);
+		}
+		if config.active.is_none() && config.passphrase.is_none() {
+			return Err("config file contains neither key_hex, a passphrase nor an active key in the keyring".into());
+		}
+		if config.padding == Padding::Block(0) {
+			return Err("the block size of the padding has to be at least 1".into());
+		}
+		match config.pack_size {
+			Some(pack_size) if pack_size < L_PACK_MIN => {
+				return Err(format!("pack_size has to be at least {} bytes", L_PACK_MIN).into());
+			}
+			None if config.decoys > 0 => return Err("decoys need a pack_size".into()),
+			_ => {}
+		}
 		Ok(config)
 	}
+
+	/// how new blobs are written
+	fn blob_format(&self) -> BlobFormat {
+		BlobFormat {
+			per_file_keys: self.per_file_keys,
+			compression: self.compression,
+			padding: self.padding,
+			pack_size: self.pack_size,
+			decoys: self.decoys,
+		}
+	}
+
+	/// write a new key into the config file as the active key of the keyring, keeping everything else as
+	/// it is. The other keys stay in the keyring, such that data under them can still be read. A key_hex is
+	/// moved into the keyring. The file is assembled in a buffer that is overwritten with zeros afterwards.
+	fn write_key(fname: &Path, key: &Key) -> Result<()> {
+		let string = SecretString(fs::read_to_string(fname)?);
+		let has_keyring = string.lines().any(|line| line.trim() == "[[keyring]]");
+		let mut lines = Vec::new();
+		let mut key_hex = None;			// key_hex outside of the keyring
+		let mut table = "";
+		let mut end_of_keyring = 0;		// index after the last line of the last keyring entry
+		for line in string.lines() {
+			if let Some(header) = toml_table(line) {
+				table = header;
+			}
+			if table.is_empty() && !has_keyring && toml_key(line) == Some("key_hex") {
+				key_hex = Some(line.trim());
+				continue;
+			}
+			if table == "[[keyring]]" {
+				if !line.trim().is_empty() {
+					end_of_keyring = lines.len() + 1;
+				}
+				if toml_key(line) == Some("active") {
+					lines.push("active = false");
+					continue;
+				}
+			}
+			lines.push(line);
+		}
+		if !has_keyring {
+			key_hex.ok_or("no key_hex or keyring in config file")?;
+			end_of_keyring = lines.len();
+		}
+		let mut out = SecretString(String::with_capacity(string.len() + 256));
+		for (i, line) in lines.iter().chain(std::iter::once(&"")).enumerate() {
+			if i == end_of_keyring {
+				if let Some(key_hex) = key_hex {
+					out.0.push_str("\n[[keyring]]\n");
+					out.0.push_str(key_hex);
+					out.0.push_str("\nactive = false\n");
+				}
+				out.0.push_str("\n[[keyring]]\nid = \"");
+				out.0.push_str(&slice_to_hex(&key_id(key)));
+				out.0.push_str("\"\nkey_hex = \"");
+				out.0.push_str(&key_to_hex(key));
+				out.0.push_str("\"\nactive = true\n");
+			}
+			if i < lines.len() {
+				out.0.push_str(line);
+				out.0.push('\n');
+			}
+		}
+		fs::write(fname, out.as_bytes())?;
+		Ok(())
+	}
+
+	/// parameters for a new key derivation. None if the key is not derived from a passphrase.
+	fn new_kdf(&self) -> Option<KdfParams> {
+		self.passphrase.as_ref().map(|_| KdfParams::new())
+	}
+
+	/// return the keyring. If the active key is derived from a passphrase, `kdf` holds the
+	/// parameters from the header of the remote image. `confirm` asks twice when prompting for a
+	/// new passphrase.
+	fn keyring(&self, kdf: Option<&KdfParams>, confirm: bool) -> Result<Keyring> {
+		if let Some(active) = self.active {
+			return Ok(Keyring { keys: self.keys.clone(), active });
+		}
+		let kdf = kdf.ok_or("the key is derived from a passphrase, but the remote image has no salt. \
+			Was it written with key_hex?")?;
+		let passphrase = self.read_passphrase(confirm)?;
+		println!("deriving key from passphrase...");
+		let mut keys = vec![kdf.derive(&passphrase)?];
+		keys.extend(self.keys.iter().cloned());
+		Ok(Keyring { keys, active: 0 })
+	}
+
+	/// key of the local image: none, the master key or a key of this device. The key file of the
+	/// device is created if it does not exist.
+	fn local_key(&self) -> Result<LocalKey> {
+		let source = match &self.local_image_key {
+			None => return Ok(LocalKey::Plain),
+			Some(source) => source,
+		};
+		if source == "master" {
+			return Ok(LocalKey::Master);
+		}
+		let fname = source.strip_prefix("file:").ok_or_else(|| format!(
+			"local_image_key = {:?} in config file is neither \"master\" nor \"file:PATH\"", source))?;
+		match fs::read_to_string(fname) {
+			Ok(string) => {
+				let key_hex = string.trim();
+				if key_hex.len() != 2*L_KEY {
+					return Err(format!("{} does not contain a key of {} hex digits", fname, 2*L_KEY).into());
+				}
+				Ok(LocalKey::Device(Config::hex_to_key(key_hex)?))
+			}
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+				let key = Key::random()?;
+				let mut options = fs::OpenOptions::new();
+				options.write(true).create_new(true);
+				#[cfg(unix)]
+				std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+				let mut line = key_to_hex(&key);
+				line.0.push('\n');
+				options.open(fname)?.write_all(line.as_bytes())?;
+				println!("created key for the local image in {}", fname);
+				Ok(LocalKey::Device(key))
+			}
+			Err(e) => Err(format!("could not read key for the local image from {}: {}", fname, e).into()),
+		}
+	}
+
+	/// the conflict policies with the globs of the rules compiled
+	fn conflict_policies(&self) -> Result<ConflictPolicies> {
+		let policies = std::iter::once(&self.conflict).chain(self.conflict_rules.iter().map(|rule| &rule.policy));
+		if policies.into_iter().any(|policy| matches!(policy, ConflictPolicy::Merge(command) if command.trim().is_empty())) {
+			return Err("empty merge command in config file".into());
+		}
+		let mut rules = Vec::new();
+		for rule in &self.conflict_rules {
+			let pattern = glob::Pattern::new(&rule.glob)
+				.map_err(|e| format!("conflict rule with glob {:?} in config file: {}", rule.glob, e))?;
+			rules.push((pattern, rule.policy.clone()));
+		}
+		Ok(ConflictPolicies { default: self.conflict.clone(), rules })
+	}
+
+	/// print the active key as a code for `symsync key import` on another device. The code is not kept.
+	pub fn export_key(&self) -> Result<()> {
+		let active = self.active.ok_or("the key is derived from a passphrase. Use the passphrase on the other device.")?;
+		println!("{}", &*key_to_code(&self.keys[active]));
+		Ok(())
+	}
+
+	/// read a code from `symsync key export` from stdin and write the key into the config file
+	/// `fname` as the new active key of the keyring. The old keys stay in the keyring.
+	pub fn import_key(&self, fname: &Path) -> Result<()> {
+		println!("key (from `symsync key export`):");
+		let mut code = SecretString::default();
+		io::stdin().read_line(&mut code.0)?;
+		let key = code_to_key(&code)?;
+		if self.keys.contains(&key) {
+			return Err("this key is already in the config file".into());
+		}
+		Config::write_key(fname, &key)?;
+		println!("imported key {} with fingerprint {}", key, fingerprint(&key_check(&key)));
+		Ok(())
+	}
+
+	/// print the fingerprints of the keys of this device and of the key of the remote folder, such
+	/// that they can be compared between devices. The remote folder is not pulled.
+	pub fn print_fingerprints(&self) -> Result<()> {
+		let header = Image::remote_header(&self.gpath).ok().flatten();
+		let kdf = match &header {
+			Some(header) => header.kdf()?,
+			None => None,
+		};
+		let keyring = self.keyring(kdf.as_ref(), false)?;
+		for (i, key) in keyring.keys.iter().enumerate() {
+			let status = if i == keyring.active {"active"} else {"old"};
+			println!("this device:   {}  ({} key {})", fingerprint(&key_check(key)), status, key);
+		}
+		match header.as_ref().map(Header::key_check).transpose()?.flatten() {
+			Some(check) => println!("remote folder: {}", fingerprint(check)),
+			None => println!("remote folder: no fingerprint. It is written with the next update."),
+		}
+		Ok(())
+	}
+
+	/// get the passphrase from the terminal, an environment variable or a file
+	fn read_passphrase(&self, confirm: bool) -> Result<SecretString> {
+		let source = self.passphrase.as_deref().unwrap_or("prompt");
+		let passphrase = if source == "prompt" {
+			let passphrase = SecretString(rpassword::read_password_from_tty(Some("passphrase: "))?);
+			if confirm && *SecretString(rpassword::read_password_from_tty(Some("repeat passphrase: "))?) != *passphrase {
+				return Err("passphrases do not match".into());
+			}
+			passphrase
+		} else if let Some(name) = source.strip_prefix("env:").or(if source == "env" {Some(ENV_PASSPHRASE)} else {None}) {
+			SecretString(env::var(name).map_err(|e| format!("could not read passphrase from {}: {}", name, e))?)
+		} else if let Some(fname) = source.strip_prefix("file:") {
+			let mut string = SecretString::default();
+			myopen(Path::new(fname))?.read_to_string(&mut string.0)?;
+			SecretString(string.lines().next().unwrap_or("").to_string())
+		} else {
+			return Err(format!("passphrase = {:?} in config file is neither \"prompt\", \"env\", \"env:NAME\" nor \"file:PATH\"", source).into());
+		};
+		if passphrase.is_empty() {
+			return Err("empty passphrase".into());
+		}
+		Ok(passphrase)
+	}
+}
+
+
+/// Key of the local image in .sync/, as chosen with `local_image_key` in the config file.
+enum LocalKey {
+	/// the local image is stored in clear
+	Plain,
+	/// the local image is encrypted with a subkey of the master key
+	Master,
+	/// the local image is encrypted with a key that never leaves this device
+	Device(Key),
+}
+
+impl LocalKey {
+	/// the keys for the local image, given the keys of the remote folder. None if it is not encrypted.
+	fn keyring(&self, master: &Keyring) -> Option<Keyring> {
+		match self {
+			LocalKey::Plain => None,
+			LocalKey::Master => Some(master.clone()),
+			LocalKey::Device(key) => Some(Keyring::single(key.clone())),
+		}
+	}
+}
+
+
+/// What to do with a file that changed both locally and remotely, as chosen with `conflict` and
+/// `conflict_rules` in the config file.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum ConflictPolicy {
+	/// the local version is renamed with the id of this device and the time, the remote one is pulled
+	#[default]
+	KeepBoth,
+	/// the local version is pushed
+	Local,
+	/// the remote version is pulled
+	Remote,
+	/// the version that was modified last is kept
+	Newest,
+	/// the command is run with `{local}` and `{remote}` replaced by the paths of both versions. It
+	/// writes the merged file to `{local}`, which is pushed. If it fails, both are kept.
+	Merge(String),
+}
+
+/// A policy for the files that match `glob`, e.g. `**/*.org`. The paths are relative to the local folder.
+#[derive(Deserialize, Debug)]
+struct ConflictRule {
+	glob: String,
+	policy: ConflictPolicy,
+}
+
+/// The conflict policies from the config file. The first rule that matches a file wins.
+#[derive(Debug)]
+struct ConflictPolicies {
+	default: ConflictPolicy,
+	rules: Vec<(glob::Pattern, ConflictPolicy)>,
+}
+
+impl ConflictPolicies {
+	fn for_file(&self, fname: &Path) -> &ConflictPolicy {
+		let fname = fname.strip_prefix(".").unwrap_or(fname);
+		let options = glob::MatchOptions { require_literal_separator: true, ..glob::MatchOptions::new() };
+		self.rules.iter()
+			.find(|(pattern, _)| pattern.matches_path_with(fname, options))
+			.map_or(&self.default, |(_, policy)| policy)
+	}
 }
 
 
+/// New key of an unfinished `symsync rekey`, saved in .sync/ before the remote folder is touched,
+/// such that an interrupted rekey can be continued. For passphrase-derived keys only the
+/// parameters are saved and the passphrase is asked again.
+#[derive(Serialize, Deserialize, Debug)]
+struct PendingKey {
+	key_hex: Option<SecretString>,
+	kdf_hex: Option<String>,
+}
+
+impl PendingKey {
+	fn path() -> PathBuf {
+		[FOLDER_SYNC, REKEY_PENDING].iter().collect()
+	}
+
+	/// return the new key. It is either loaded from an unfinished rekey or newly generated: a random
+	/// key if the config has key_hex and a key from a new passphrase otherwise.
+	fn load_or_create(config: &Config) -> Result<(Key, Option<KdfParams>)> {
+		if let Ok(string) = fs::read_to_string(PendingKey::path()).map(SecretString) {
+			println!("continuing unfinished rekey");
+			let pending: PendingKey = serde_json::from_str(&string)?;
+			if let Some(key_hex) = pending.key_hex {
+				return Ok((Config::hex_to_key(&key_hex)?, None));
+			}
+			let kdf = KdfParams::from_bytes(&hex_to_vec(&pending.kdf_hex.ok_or("rekey.json is empty")?)?)?;
+			println!("please enter the new passphrase");
+			let key = kdf.derive(&config.read_passphrase(false)?)?;
+			return Ok((key, Some(kdf)));
+		}
+		let (key, kdf, pending) = match config.new_kdf() {
+			None => {
+				let key = Key::random()?;
+				let key_hex = key_to_hex(&key);
+				(key, None, PendingKey { key_hex: Some(key_hex), kdf_hex: None })
+			}
+			Some(kdf) => {
+				println!("please choose a new passphrase");
+				let passphrase = config.read_passphrase(true)?;
+				let pending = PendingKey { key_hex: None, kdf_hex: Some(slice_to_hex(&kdf.to_bytes())) };
+				(kdf.derive(&passphrase)?, Some(kdf), pending)
+			}
+		};
+		// the new key is in clear until the rekey is finished, so only the owner may read it
+		let mut options = fs::OpenOptions::new();
+		options.write(true).create(true).truncate(true);
+		#[cfg(unix)]
+		std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+		options.open(PendingKey::path())?.write_all(SecretString(serde_json::to_string(&pending)?).as_bytes())?;
+		Ok((key, kdf))
+	}
+}
+
 /// Info about a single file. 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Metadata {
@@ -142,8 +1230,81 @@ struct Metadata {
 	namehash: String,		// hashed name
 	modified: u64, 			// modification time in seconds since the epoche; this can as well be the time of the last pull
 	actually_modified: u64, // time the file was actually modified
-	signature: String, 		// Signature of the file (from SipHash)
-	iv: Iv,		 			// IV used for encryption
+	signature: String, 		// HMAC of the blob (see signature_type)
+	iv: Option<Iv>,			// IV used for CBC encryption. AES-GCM blobs carry their own nonce
+	#[serde(default)]
+	algorithm: Algorithm,	// cipher of the blob
+	#[serde(default)]
+	signature_type: SignatureType,
+	#[serde(default)]
+	pieces: Option<Vec<String>>,	// names of the pieces of the file. None for blobs that hold the whole file
+	#[serde(default)]
+	hash: Option<String>,	// SHA3-256 of the content. None for entries written by older versions
+	#[serde(default)]
+	stat: Option<Stat>,		// what the file system told about the local file at the last sync
+	#[serde(default)]
+	version: BTreeMap<String, u64>,	// version vector: how often each device pushed the file. Empty for older versions
+	#[serde(default)]
+	written_by: Option<String>,	// device that pushed this version
+}
+
+/// What the file system tells about a file. If it is the same as at the last sync, the file is
+/// unchanged and is not read. The ctime catches tools that restore the mtime after writing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Stat {
+	size: u64,
+	mtime_ns: u64,
+	ctime_ns: u64,		// 0 where there is no ctime
+	inode: u64,			// 0 where there are no inodes
+}
+
+impl Stat {
+	fn of(attr: &fs::Metadata) -> Result<Self> {
+		let mtime_ns = attr.modified()?.duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+		#[cfg(unix)]
+		let (ctime_ns, inode) = {
+			use std::os::unix::fs::MetadataExt;
+			((attr.ctime() as u64).wrapping_mul(1_000_000_000).wrapping_add(attr.ctime_nsec() as u64), attr.ino())
+		};
+		#[cfg(not(unix))]
+		let (ctime_ns, inode) = (0, 0);
+		Ok(Stat { size: attr.len(), mtime_ns, ctime_ns, inode })
+	}
+}
+
+/// The part of `Metadata` that is embedded encrypted in every blob, such that the image can be rebuilt
+/// if it is lost. Everything else follows from the blob itself.
+#[derive(Serialize, Deserialize, Debug)]
+struct EmbeddedMetadata {
+	name: String,
+	modified: u64,
+	actually_modified: u64,
+	#[serde(default)]
+	pieces: bool,		// the blob holds the list of pieces instead of the content
+	#[serde(default)]
+	version: BTreeMap<String, u64>,
+}
+
+impl Metadata {
+	/// associated data of the blob. This binds the ciphertext to the entry in the image, such that
+	/// blobs cannot be swapped or replayed under another name.
+	fn aad(&self) -> Vec<u8> {
+		format!("{}\0{}\0{}", self.namehash, self.name, self.actually_modified).into_bytes()
+	}
+
+	/// whether both entries describe the same version of the file. Entries of older versions have no
+	/// version vector; two devices can write a file within the same second, so the content hash
+	/// decides where both have one.
+	fn same_version(&self, other: &Metadata) -> bool {
+		if !self.version.is_empty() && !other.version.is_empty() {
+			return self.version == other.version;
+		}
+		self.actually_modified == other.actually_modified
+			&& match (&self.hash, &other.hash) {
+				(Some(a), Some(b)) => a == b,
+				_ => true,
+			}
+	}
 }
 
 
@@ -152,8 +1313,155 @@ struct Metadata {
 #[derive(Serialize, Deserialize, Debug)]
 struct Image {
 	last_update: u64, 					// time of last sync
-	siphashkey: (u64, u64),				// key used for the namehash
+	#[serde(default)]
+	siphashkey: Option<(u64, u64)>,		// random key for the namehash of older images. Newer ones derive it from the master key
+	#[serde(default)]
+	generation: u64,					// incremented every time the remote image is written
+	#[serde(default)]
+	naming: Naming,						// naming of the blobs
+	#[serde(default)]
+	name_key: Option<String>,			// id of the key that encrypts the names of the blobs with naming "siv"
 	filesystem: Vec<Metadata>,
+	#[serde(default)]
+	packs: Vec<Pack>,					// packs in `gpath`. A blob in a pack is read from there, not from its own file
+	#[serde(default)]
+	decoys: Vec<String>,				// empty packs in `gpath`
+	#[serde(default)]
+	devices: Vec<String>,				// ids of the devices that sync with `gpath`
+	#[serde(default)]
+	tombstones: Vec<Tombstone>,			// deleted files, until every device has seen the deletion
+	#[serde(default)]
+	quarantine: Vec<String>,			// blobs in `gpath` that `recover` could not attribute. They are never deleted
+}
+
+/// Record of a deleted file, such that every device deletes it, even one that has no entry for it
+/// in its local image. It is dropped once all devices in `Image::devices` have seen it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Tombstone {
+	name: String,				// path and name of the file
+	hash: Option<String>,		// content hash of the deleted version
+	deleted_by: String,			// id of the device that deleted it
+	deleted_at: u64,			// time of the deletion in seconds since the epoch
+	seen_by: Vec<String>,		// ids of the devices that have seen it
+	#[serde(default)]
+	version: BTreeMap<String, u64>,	// version vector of the deleted version
+}
+
+/// A file in `gpath` that holds several small blobs, such that the server only sees files of the same size.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Pack {
+	name: String,				// random name
+	blobs: Vec<(String, u32)>,	// names and lengths of the blobs in the pack that are still in use
+}
+
+impl Pack {
+	/// bytes used by the blobs in the pack
+	fn used(&self) -> usize {
+		self.blobs.iter().map(|(name, l)| packed_len(name, *l as usize)).sum()
+	}
+}
+
+/// State of a file on one side, the local folder or the remote image, compared with its base: the
+/// entry in the local image from the last sync.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+	/// the file is not there
+	Absent,
+	/// the file is the same as at the last sync
+	Unchanged,
+	/// the file was modified since the last sync, or created if there is no base
+	Changed,
+	/// the remote image has a tombstone for the file. Only on the remote side
+	Deleted,
+}
+
+/// What `Jambon::sync` does with a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Decision {
+	/// both sides are as at the last sync
+	Nothing,
+	/// encrypt the local file and put it into the image
+	Push,
+	/// decrypt the remote file into the local folder
+	Pull,
+	/// the file was deleted remotely: delete the local file
+	DeleteLocal,
+	/// the file was deleted locally: remove it from the image
+	DeleteRemote,
+	/// the file was deleted on both sides: remove it from the image
+	Forget,
+	/// both sides changed the file, or one changed it and the other deleted it
+	Conflict,
+}
+
+/// partial order of two version vectors: `Less` if every device pushed the file of `a` no more often
+/// than that of `b`, and `None` if both have pushes the other has not seen, i.e. they were written
+/// concurrently.
+fn compare_versions(a: &BTreeMap<String, u64>, b: &BTreeMap<String, u64>) -> Option<Ordering> {
+	let mut order = Ordering::Equal;
+	for device in a.keys().chain(b.keys()) {
+		let (x, y) = (a.get(device).unwrap_or(&0), b.get(device).unwrap_or(&0));
+		match (order, x.cmp(y)) {
+			(_, Ordering::Equal) => {}
+			(Ordering::Equal, other) => order = other,
+			(current, other) if current != other => return None,
+			_ => {}
+		}
+	}
+	Some(order)
+}
+
+/// `time` as in 2026-10-18T09:30:00Z
+fn format_utc(time: SystemTime) -> String {
+	let secs = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+	// civil date from days since the epoch, after Howard Hinnant's algorithm
+	let z = (secs / 86400) as i64 + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z.rem_euclid(146097);
+	let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+	let doy = doe - (365*yoe + yoe/4 - yoe/100);
+	let mp = (5*doy + 2) / 153;
+	let day = doy - (153*mp + 2)/5 + 1;
+	let month = if mp < 10 {mp + 3} else {mp - 9};
+	let year = yoe + era*400 + if month <= 2 {1} else {0};
+	format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs % 86400 / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/// add the pushes of `other` to `version`, such that it covers both
+fn merge_versions(version: &mut BTreeMap<String, u64>, other: &BTreeMap<String, u64>) {
+	for (device, &n) in other {
+		let count = version.entry(device.clone()).or_insert(0);
+		*count = (*count).max(n);
+	}
+}
+
+/// the decision table of the three-way sync. `base` tells if the local image has an entry for the
+/// file. Without one, every file that is there counts as created, it cannot be unchanged. Only a
+/// local file with the content of a deleted version counts as unchanged compared with its tombstone.
+fn decide(base: bool, local: Side, remote: Side) -> Decision {
+	match (base, local, remote) {
+		(_, Side::Deleted, _) => unreachable!("a local file cannot have a tombstone"),
+		(false, Side::Unchanged, Side::Deleted) => Decision::DeleteLocal,
+		(false, Side::Changed, Side::Deleted) => Decision::Push,
+		(false, Side::Absent, Side::Deleted) => Decision::Nothing,
+		(true, Side::Unchanged, Side::Deleted) => Decision::DeleteLocal,
+		(true, Side::Changed, Side::Deleted) => Decision::Conflict,
+		(true, Side::Absent, Side::Deleted) => Decision::Forget,
+		(false, Side::Unchanged, _) | (false, _, Side::Unchanged) => unreachable!("a file without base cannot be unchanged"),
+		(false, Side::Absent, Side::Absent) => Decision::Nothing,
+		(false, Side::Changed, Side::Absent) => Decision::Push,
+		(false, Side::Absent, Side::Changed) => Decision::Pull,
+		(false, Side::Changed, Side::Changed) => Decision::Conflict,
+		(true, Side::Unchanged, Side::Unchanged) => Decision::Nothing,
+		(true, Side::Changed, Side::Unchanged) => Decision::Push,
+		(true, Side::Unchanged, Side::Changed) => Decision::Pull,
+		(true, Side::Changed, Side::Changed) => Decision::Conflict,
+		(true, Side::Absent, Side::Unchanged) => Decision::DeleteRemote,
+		(true, Side::Unchanged, Side::Absent) => Decision::DeleteLocal,
+		(true, Side::Absent, Side::Changed) => Decision::Conflict,
+		(true, Side::Changed, Side::Absent) => Decision::Conflict,
+		(true, Side::Absent, Side::Absent) => Decision::Forget,
+	}
 }
 
 /// Structure to compare the local and remote images and operate on them for encryption, decryption etc.
@@ -161,124 +1469,399 @@ pub struct Jambon {
 	image_l: Option<Image>, 		// local image
 	image_r: Option<Image>, 		// remote image
 	gpath: PathBuf, 				// path to remote image
-	key: Key, 						// key from config
+	keyring: Keyring, 				// keys from config
+	kdf: Option<KdfParams>,			// parameters if the key is derived from a passphrase
+	format: BlobFormat,				// how new blobs are written
+	new_key: Option<(Key, Option<KdfParams>)>, // key and parameters after rekey
+	local_key: LocalKey,			// key of the local image
+	device: String,					// id of this device, for the tombstones
+	conflicts: ConflictPolicies,	// what to do with files that changed on both sides
 	command_push: String, 			// push command form config
 	did_something: bool, 			// for not copying the image if nothing was updated
+	failed: Vec<(PathBuf, Option<Metadata>)>,	// files that could not be synced and their remote entries
 }
 
 impl Image {
-	/// create an Image instance and calculate a random key for namehashing 
-	/// (the encryption key from the config file has nothing to do with this)
+	/// create an Image instance. The key for namehashing is derived from the master key.
 	fn new() -> Self {
-		let image = Image {
+		Image {
 			filesystem: Vec::new(),
 			last_update: 0,
-			siphashkey: gen_sipkey(),
-		};
-		image
-	}
-	/// load Image from toml in clear format. 
-	fn from_local() -> Result<Self> {
-		let path: PathBuf = [FOLDER_SYNC, IMAGE_LOCAL].iter().collect();
-		let mut f = myopen(&path)?;
-		let mut string = String::new();
-		f.read_to_string(&mut string)?;
-		let image: Image = serde_json::from_str(&string)?;
+			siphashkey: None,
+			generation: 0,
+			naming: Naming::default(),
+			name_key: None,
+			packs: Vec::new(),
+			decoys: Vec::new(),
+			devices: Vec::new(),
+			tombstones: Vec::new(),
+			quarantine: Vec::new(),
+		}
+	}
+
+	/// keep a key for the namehash in the image, derived from the active key, if it has none yet, and
+	/// the id of the key that encrypts the names. They are not changed later, such that activating
+	/// another key does not rename every blob. Images written by older versions have a random siphashkey.
+	fn pin_namekey(&mut self, keyring: &Keyring) {
+		if self.name_key.is_none() {
+			self.name_key = Some(slice_to_hex(&key_id(keyring.active())));
+		}
+		if self.siphashkey.is_none() {
+			let key = derive_key(keyring.active(), Purpose::Names, b"");
+			let mut k = [0; 8];
+			k.copy_from_slice(&key[..8]);
+			let k1 = u64::from_be_bytes(k);
+			k.copy_from_slice(&key[8..16]);
+			self.siphashkey = Some((k1, u64::from_be_bytes(k)));
+		}
+	}
+
+	/// name of the blob of a file in `gpath`
+	fn blobname(&self, filename: &Path, keyring: &Keyring) -> Result<String> {
+		match self.naming {
+			Naming::Siphash => {
+				let namekey = self.siphashkey.ok_or("the image has no key for the names")?;
+				Ok(format!("{:x}", calc_signature_sip(filename, &namekey)))
+			}
+			Naming::Siv => encrypt_name(filename.to_str().unwrap(), self.name_key(keyring)?),
+		}
+	}
+
+	/// the key that encrypts the names of the blobs
+	fn name_key<'a>(&self, keyring: &'a Keyring) -> Result<&'a Key> {
+		let id = self.name_key.as_ref().ok_or("the image has no key for the names")?;
+		keyring.keys.iter().find(|key| slice_to_hex(&key_id(key)) == *id)
+			.ok_or_else(|| format!("the names of the blobs are encrypted with key {}, which is not in the keyring", id).into())
+	}
+
+	/// the file in .sync/ with the local image
+	fn local_file() -> PathBuf {
+		[FOLDER_SYNC, IMAGE_LOCAL].iter().collect()
+	}
+
+	/// load the local Image from `path`. It is decrypted if it is encrypted, an image in clear format is
+	/// accepted as well, such that encryption can be switched on. An image that is encrypted with an old
+	/// key of the keyring, e.g. after a new key was imported, is saved again with the active key.
+	fn from_local(path: &Path, keyring: Option<&Keyring>) -> Result<Self> {
+		let buf = readfile(path)?;
+		let image = Image::from_local_bytes(&buf, keyring).map_err(|e| if e.is::<KeyIdError>() {
+			format!("{} Add the key of the local image to the keyring with active = false, \
+				or remove {:?} and run `symsync blindpull`.", e, path).into()
+		} else {
+			e
+		})?;
+		if let Some(keyring) = keyring.filter(|_| buf.starts_with(MAGIC)) {
+			let (header, _) = Header::from_bytes(&buf)?;
+			if header.key_id != key_id(keyring.active()) {
+				println!("the local image is encrypted with the old key {}, encrypting it with key {}",
+					slice_to_hex(&header.key_id), keyring.active());
+				fs::write(path, image.to_local_bytes(Some(keyring))?)?;
+			}
+		}
 		Ok(image)
 	}
+
+	fn from_local_bytes(buf: &[u8], keyring: Option<&Keyring>) -> Result<Self> {
+		if !buf.starts_with(MAGIC) {
+			return Ok(serde_json::from_slice(buf)?);
+		}
+		let keyring = keyring.ok_or("the local image is encrypted. Please set local_image_key in the config file.")?;
+		let message = my_decrypt(buf, keyring, IMAGE_LOCAL.as_bytes())?;
+		Ok(serde_json::from_slice(&message)?)
+	}
+
+	/// the file in .sync/ with the highest generation this device has seen
+	fn generation_file() -> PathBuf {
+		[FOLDER_SYNC, GENERATION].iter().collect()
+	}
+
+	/// highest generation of the remote image this device has seen, from `path`. 0 if it has not seen any.
+	fn seen_generation(path: &Path) -> Result<u64> {
+		match fs::read_to_string(path) {
+			Ok(string) => Ok(string.trim().parse()?),
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// remember the generation of the remote image in `path`
+	fn save_generation(path: &Path, generation: u64) -> Result<()> {
+		fs::write(path, format!("{}\n", generation))?;
+		Ok(())
+	}
+
+	/// refuse a remote image that is older than one this device has already seen. Otherwise remember
+	/// its generation in `path`.
+	///
+	/// An image of the same generation is accepted: two devices that push at the same time both write
+	/// the generation after the one they have seen, and the version vectors of the files sort out
+	/// which changes of the other were lost. Replaying the image this device has seen does not roll
+	/// anything back.
+	fn check_generation(&self, path: &Path) -> Result<()> {
+		let seen = Image::seen_generation(path)?;
+		if self.generation < seen {
+			return Err(RollbackError { seen, found: self.generation }.into());
+		}
+		Image::save_generation(path, self.generation)
+	}
+
+	/// read the header of the remote image. None for images written by older versions without header.
+	fn remote_header(gpath: &Path) -> Result<Option<Header>> {
+		let mut path = PathBuf::from(gpath);
+		path.push(IMAGE_REMOTE);
+		let buf = readfile(&path)?;
+		if !buf.starts_with(MAGIC) {
+			return Ok(None);
+		}
+		let (header, _) = Header::from_bytes(&buf)?;
+		Ok(Some(header))
+	}
+
 	/// load Image from toml in encrypted format. 
-	fn from_remote(gpath: &Path, key: &Key) -> Result<Self> {
+	///
+	/// Images written by older versions (without header, or aes_256_cbc with the IV appended) can still be read.
+	fn from_remote(gpath: &Path, keyring: &Keyring) -> Result<Self> {
 		let mut path = PathBuf::from(gpath);
 		path.push(IMAGE_REMOTE);
-		let mut f = myopen(&path).expect("could not open image file in from_remote");
-		let mut buf = Vec::new();
-		f.read_to_end(&mut buf).expect("could not read image in remote directory");
-		let l = buf.len();
-		let iv = Iv::try_from(&buf[l-L_IV..]).expect("something wrong with IV in image in remote");
-		let message = my_decrypt(&buf[..l-L_IV], &key, &iv).expect("my_decrypt failed in from_remote");
-		let message = String::from_utf8(message).expect("from_utf8 failed in from_remote");
-        //println!("message: {:?}", &message);
-        match serde_json::from_str(&message) {
-            Ok(image) => {return Ok(image)}
-            Err(e) => {println!("deserialization from json failed in from_remote. Error message: {:?}.", e); return Err(e.into())}
-        }
+		let buf = readfile(&path)?;
+		let message = match my_decrypt(&buf, keyring, IMAGE_REMOTE.as_bytes()) {
+			Ok(message) => message,
+			Err(e) => {
+				if buf.starts_with(MAGIC) || buf.len() < L_IV {
+					return Err(e);
+				}
+				let l = buf.len();
+				let iv = Iv::try_from(&buf[l-L_IV..])?;
+				// if the image is neither a valid AES-GCM nor a CBC image, report the tag failure.
+				keyring.candidates().into_iter()
+					.filter_map(|key| my_decrypt_cbc(&buf[..l-L_IV], key, &iv).ok())
+					.find(|message| serde_json::from_slice::<Image>(message).is_ok())
+					.ok_or(e)?
+			}
+		};
+		serde_json::from_slice(&message).map_err(|e| format!("the remote image is damaged: {}", e).into())
 	}
 
-	/// add a file to Image. This will calculate the hashed name and signature of the file. 
-	/// It will also check when the file was last modified
-	fn push(&mut self, filename: &Path, content: &Vec<u8>, iv: Iv) -> io::Result<&mut Self> {
-		let attr = fs::metadata(&filename)?;
+	/// create the metadata for a file with the name of its blob from `blobname`.
+	/// It will also check when the file was last modified.
+	/// The signature is left empty until the file is encrypted.
+	fn metadata(filename: &Path, namehash: String) -> io::Result<Metadata> {
+		let attr = fs::metadata(filename)?;
 		let modtime = attr.modified().unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs();
 		let meta = Metadata {
 			name: filename.to_str().unwrap().to_string(),
-			namehash: format!("{:x}", calc_signature_sip(&filename, &self.siphashkey)),
+			namehash,
 			modified: modtime,
-			actually_modified: modtime.clone(),
-			signature: format!("{:x}", calc_signature_sip(&content, &self.siphashkey)),
-			iv: iv,//slice_to_hex(&iv[..]),
+			actually_modified: modtime,
+			signature: String::new(),
+			iv: None,
+			algorithm: Algorithm::Aes256GcmStream,
+			signature_type: SignatureType::HmacSha3,
+			pieces: None,
+			hash: None,
+			stat: Stat::of(&attr).ok(),
+			version: BTreeMap::new(),
+			written_by: None,
 		};
+		Ok(meta)
+	}
+
+	/// add the metadata of a file to Image
+	fn push(&mut self, meta: Metadata) -> &mut Self {
 		self.filesystem.push(meta);
 		self.last_update = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-		Ok(self)
+		self
 	}
 	
 	/// update the metadata of an existing entry
-	fn update(&mut self, filename: &Path, content: &Vec<u8>, iv: Iv, idx: usize) -> io::Result<&mut Self> {
-		let attr = fs::metadata(&filename)?;
-		let modtime = attr.modified().unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs();
-		let meta = Metadata {
-			name: filename.to_str().unwrap().to_string(),
-			namehash: format!("{:x}", calc_signature_sip(&filename, &self.siphashkey)),
-			modified: modtime,
-			actually_modified: modtime.clone(),
-			signature: format!("{:x}", calc_signature_sip(&content, &self.siphashkey)),
-			iv: iv,//slice_to_hex(&iv[..]),
-		};
+	fn update(&mut self, meta: Metadata, idx: usize) -> &mut Self {
 		self.filesystem[idx] = meta;
 		self.last_update = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-		Ok(self)
+		self
 	}
 
-	/// change the siphashkey. This is in case there is a conflict between the remote and local siphashkey. 
-	/// This happens after blindpush because a new siphashkey gets generated.
-	fn update_siphashkey(&mut self, skey: &(u64, u64)) {
-		self.siphashkey = skey.clone();
-		for mut metadata in &mut self.filesystem {
-			metadata.namehash = format!("{:x}", calc_signature_sip(&metadata.name, &self.siphashkey));
+	/// take the siphashkey, the naming and the key for the names from the remote image. This is in case there is
+	/// a conflict between the remote and local naming. This happens after blindpush because the siphashkey of
+	/// older images was random.
+	fn update_naming(&mut self, remote: &Image, keyring: &Keyring) -> Result<()> {
+		self.siphashkey = remote.siphashkey;
+		self.naming = remote.naming;
+		self.name_key = remote.name_key.clone();
+		self.pin_namekey(keyring);
+		for i in 0..self.filesystem.len() {
+			// hash the name as a path, like `metadata` does
+			let namehash = self.blobname(Path::new(&self.filesystem[i].name), keyring)?;
+			self.filesystem[i].namehash = namehash;
 		}
-		
+		Ok(())
 	}
 
-	/// save Image as toml in clear format to local folder
-	fn save_local(&self) -> Result<()>{
-		let j = serde_json::to_string(&self)?;
-		let path: PathBuf = [FOLDER_SYNC, IMAGE_LOCAL].iter().collect();
-		let mut f = File::create(path)?;
-		f.write_all(j.as_bytes())?;
+	/// take the devices and tombstones from the remote image, which has the latest ones
+	fn adopt_tombstones(&mut self, remote: &Image) {
+		self.devices = remote.devices.clone();
+		self.tombstones = remote.tombstones.clone();
+	}
+
+	fn tombstone(&self, fname: &Path) -> Option<&Tombstone> {
+		self.tombstones.iter().find(|tombstone| Path::new(&tombstone.name) == fname)
+	}
+
+	/// add `device` to the known devices and mark every tombstone as seen by it. Tombstones that all
+	/// devices have seen are dropped. Return true if anything changed.
+	fn register(&mut self, device: &str) -> bool {
+		let mut changed = false;
+		if !self.devices.iter().any(|d| d == device) {
+			self.devices.push(device.to_string());
+			changed = true;
+		}
+		for tombstone in &mut self.tombstones {
+			if !tombstone.seen_by.iter().any(|d| d == device) {
+				tombstone.seen_by.push(device.to_string());
+				changed = true;
+			}
+		}
+		let devices = &self.devices;
+		self.tombstones.retain(|tombstone| !devices.iter().all(|d| tombstone.seen_by.contains(d)));
+		changed
+	}
+
+	/// take the blob of every unchanged file from the remote image. The blobs may have been renamed
+	/// or re-encrypted by `symsync rekey`, or packed by another device.
+	fn adopt_blobs(&mut self, remote: &Image) {
+		self.packs = remote.packs.clone();
+		self.decoys = remote.decoys.clone();
+		self.quarantine = remote.quarantine.clone();
+		self.name_key = remote.name_key.clone();
+		for metadata_r in &remote.filesystem {
+			if let Some(idx) = self.get_index(Path::new(&metadata_r.name)) {
+				let metadata_l = &mut self.filesystem[idx];
+				if metadata_l.same_version(metadata_r) {
+					metadata_l.namehash = metadata_r.namehash.clone();
+					metadata_l.signature = metadata_r.signature.clone();
+					metadata_l.signature_type = metadata_r.signature_type;
+					metadata_l.algorithm = metadata_r.algorithm;
+					metadata_l.iv = metadata_r.iv;
+					metadata_l.pieces = metadata_r.pieces.clone();
+				}
+			}
+		}
+	}
+
+	/// save Image to the local folder, encrypted with the active key of `keyring` if given and
+	/// in clear format otherwise
+	fn save_local(&self, keyring: Option<&Keyring>) -> Result<()>{
+		let mut f = File::create(Image::local_file())?;
+		f.write_all(&self.to_local_bytes(keyring)?)?;
 		Ok(())
 	}
+
+	fn to_local_bytes(&self, keyring: Option<&Keyring>) -> Result<Vec<u8>> {
+		let j = serde_json::to_string(&self)?;
+		match keyring {
+			Some(keyring) => my_encrypt(j.as_bytes(), keyring.active(), IMAGE_LOCAL.as_bytes(), Purpose::LocalImage, b""),
+			None => Ok(j.into_bytes()),
+		}
+	}
 	
-	/// save Image as toml in encrytped format to remote folder
-	fn save_remote(&self, gpath: &Path, key: &Key) -> Result<()> {
+	/// save Image as toml in encrytped format to remote folder. 
+	/// If the key is derived from a passphrase, `kdf` is stored in the header.
+	/// The generation is remembered, such that older images are refused afterwards.
+	fn save_remote(&self, gpath: &Path, key: &Key, kdf: Option<&KdfParams>, padding: Padding) -> Result<()> {
 		let j = serde_json::to_string(&self)?;
         //println!("message: {:?}", j);
-		let (mut cipher, iv) = my_encrypt(&j.as_bytes(), key)?;
-		cipher.extend(&iv);
+		let mut extensions = kdf.map(|kdf| vec![(EXT_KDF, kdf.to_bytes())]).unwrap_or_default();
+		extensions.extend(padding.extension());
+		extensions.push((EXT_KEY_CHECK, key_check(key).to_vec()));
+		let cipher = my_encrypt_ext(j.as_bytes(), key, IMAGE_REMOTE.as_bytes(), Purpose::Image, b"", extensions)?;
 		let mut path = PathBuf::from(&gpath);
 		path.push(IMAGE_REMOTE);
 		let mut f = File::create(path)?;
 		f.write_all(&cipher)?;
-		Ok(())
+		Image::save_generation(&Image::generation_file(), self.generation)
+	}
+
+	/// replace the entry of `fname` with `entry`, or remove it if that is None. Returns the entry it had.
+	fn set_entry(&mut self, fname: &Path, entry: Option<Metadata>) -> Option<Metadata> {
+		let old = self.get_index(fname).map(|idx| self.filesystem.remove(idx));
+		self.filesystem.extend(entry);
+		old
 	}
 
 	/// return the index at which file fname is stored. None if there is no such file.
 	fn get_index(&self, fname: &Path) -> Option<usize> {
-		for i in 0..self.filesystem.len() {
-			if fname.to_str().unwrap() == self.filesystem[i].name {
-				return Some(i)
+		(0..self.filesystem.len()).find(|&i| fname.to_str().unwrap() == self.filesystem[i].name)
+	}
+
+	/// names of the blobs that are in a pack
+	fn packed(&self) -> HashSet<&str> {
+		self.packs.iter().flat_map(|pack| pack.blobs.iter().map(|(name, _)| name.as_str())).collect()
+	}
+
+	/// the blob `name` was written to a file of its own, which replaces the one in a pack
+	fn unpack(&mut self, name: &str) {
+		for pack in &mut self.packs {
+			pack.blobs.retain(|(blobname, _)| blobname != name);
+		}
+	}
+
+	/// put the pieces and lists of pieces into packs of `format.pack_size` bytes and keep `format.decoys`
+	/// decoys. Blobs that are no longer used are dropped from the packs and packs that are less than half
+	/// full are filled up again. The files of the blobs that were packed are deleted by `clean_remote`.
+	fn pack(&mut self, gpath: &Path, keyring: &Keyring, key: &Key, kdf: Option<&KdfParams>, format: &BlobFormat) -> Result<()> {
+		let referenced = self.referenced();
+		let quarantine = &self.quarantine;
+		for pack in &mut self.packs {
+			pack.blobs.retain(|(name, _)| referenced.contains(name) || quarantine.contains(name));
+		}
+		self.packs.retain(|pack| !pack.blobs.is_empty());
+		self.decoys.truncate(format.decoys as usize);
+		let pack_size = match format.pack_size {
+			Some(pack_size) => pack_size as usize,
+			None => return Ok(()),
+		};
+		let mut packer = Packer::new(gpath, pack_size, key, kdf, format)?;
+		let mut packed: HashSet<String> = self.packed().into_iter().map(String::from).collect();
+		let mut loose = Vec::new();
+		for metadata in self.filesystem.iter().filter(|metadata| metadata.pieces.is_some()) {
+			for name in metadata.pieces.iter().flatten().chain(std::iter::once(&metadata.namehash)) {
+				let path = gpath.join(name);
+				if !packed.contains(name) && path.exists()
+					&& packed_len(name, fs::metadata(&path)?.len() as usize) <= packer.capacity {
+					packed.insert(name.clone());
+					loose.push(name.clone());
+				}
 			}
 		}
-		None
+		let (underfull, full): (Vec<Pack>, Vec<Pack>) = self.packs.drain(..)
+			.partition(|pack| pack.used() * 2 < packer.capacity);
+		self.packs = full;
+		if loose.is_empty() && underfull.len() < 2 {
+			self.packs.extend(underfull);
+		} else {
+			for pack in underfull {
+				for (name, blob) in open_pack(&readfile(&gpath.join(&pack.name))?, &pack.name, keyring)? {
+					if pack.blobs.iter().any(|(blobname, _)| *blobname == name) {
+						packer.add(name, blob)?;
+					}
+				}
+			}
+			for name in loose {
+				let blob = readfile(&gpath.join(&name))?;
+				packer.add(name, blob)?;
+			}
+			packer.flush()?;
+		}
+		let changed = !packer.packs.is_empty();
+		self.packs.append(&mut packer.packs);
+		while self.decoys.len() < format.decoys as usize {
+			self.decoys.push(packer.write(&[])?);
+		}
+		// a decoy is replaced along with the packs, such that new packs do not stand out
+		if changed && !self.decoys.is_empty() {
+			let i = rand::thread_rng().gen_range(0, self.decoys.len());
+			self.decoys[i] = packer.write(&[])?;
+		}
+		Ok(())
 	}
 
 	/// return a list of all filenames in Image
@@ -291,14 +1874,15 @@ impl Image {
 		files
 	}
 	
-	/// return a list of all hashed filenames in Image
-	fn get_hashnames(&self) -> Vec<PathBuf> {
-		let mut files = Vec::new();
+	/// names of the blobs that are referenced by an entry. Pieces are named after their content, so the
+	/// same piece can be referenced by several files, or several times by one file.
+	fn referenced(&self) -> HashSet<String> {
+		let mut names = HashSet::new();
 		for metadata in &self.filesystem {
-			let file = PathBuf::from(&metadata.namehash);
-			files.push(file);
+			names.insert(metadata.namehash.clone());
+			names.extend(metadata.pieces.iter().flatten().cloned());
 		}
-		files
+		names
 	}
 }
 
@@ -318,69 +1902,96 @@ impl Image {
 /// // set task to update for this example
 /// let goal = Goal::Update;
 ///
-///	let mut jambon = Jambon::start(config, &goal)?;
+///    let mut jambon = Jambon::start(config, &goal)?;
 ///
-///	match goal {
-///		Goal::BlindPush => {
-///			let fnames = get_filenames(&PathBuf::from("."));
-///			for fname in fnames {
-///				println!("adding {:?}", &fname);
-///				jambon.encrypt_save_add(&fname)?;
-///			}
-///		}
-///		Goal::BlindPull => {
-///			jambon.load_missing(&Vec::new())?;
-///		}
-///		Goal::Update => {
-///			let fnames = get_filenames(&PathBuf::from("."));
-///			for fname in &fnames {
-///				jambon.update(&fname)?;
-///			}
-///			jambon.clean_image(&fnames)?;
-///			jambon.load_missing(&fnames)?;
-///		}
-///	}
+///    match goal {
+///        Goal::BlindPush => {
+///            let fnames = get_filenames(&PathBuf::from("."));
+///            for fname in fnames {
+///                println!("adding {:?}", &fname);
+///                jambon.encrypt_save_add(&fname)?;
+///            }
+///        }
+///        Goal::BlindPull => {
+///            jambon.load_missing()?;
+///        }
+///        Goal::Update => {
+///            let fnames = get_filenames(&PathBuf::from("."));
+///            jambon.sync(&fnames)?;
+///        }
+///        Goal::Rekey => {
+///            jambon.rekey()?;
+///        }
+///        Goal::Recover => {
+///            jambon.recover()?;
+///        }
+///    }
 ///
-///	jambon.finish(&goal)?;
+///    jambon.finish(&goal)?;
 ///
 /// # Ok::<(), Box<dyn std::error::Error>>(())
 /// ```
-
 impl Jambon {
 	/// load the images, run `command_pull`, update siphashkey if necessary
 	pub fn start(config: Config, goal: &Goal) -> Result<Self> {
-		let key = config.key; 
-		let gpath = config.gpath;
+		let gpath = config.gpath.clone();
+		let local_key = config.local_key()?;
+		let device = Jambon::device_id()?;
+		let conflicts = config.conflict_policies()?;
+		let keyring;
+		let kdf;
 		let mut image_l;
-		let image_r;
+		let mut image_r;
+		let mut new_key = None;
 		match goal {
 			Goal::BlindPush => {
 				Jambon::cleangpath(&gpath)?;
-				image_l = Some(Image::new());
+				kdf = config.new_kdf();
+				keyring = config.keyring(kdf.as_ref(), true)?;
+				let mut image = Image::new();
+				image.generation = Image::seen_generation(&Image::generation_file())? + 1;
+				image.naming = config.naming;
+				image.register(&device);
+				image_l = Some(image);
 				image_r = None;
 			}
 			Goal::BlindPull => {
 				Jambon::gpull(&gpath, &config.command_pull)?;
-				image_r = Some(Image::from_remote(&gpath, &key).expect(
-                        format!("could not load image in {:?}", &gpath).as_str()));
+				kdf = Jambon::remote_kdf(&gpath)?;
+				keyring = config.keyring(kdf.as_ref(), false)?;
+				Jambon::check_remote_key(&gpath, &keyring)?;
+				image_r = Some(Image::from_remote(&gpath, &keyring)
+					.map_err(|e| format!("could not load image in {:?}: {}", &gpath, e))?);
+				image_r.as_ref().unwrap().check_generation(&Image::generation_file())?;
 				let mut image = Image::new();
-				image.siphashkey = image_r.as_ref().unwrap().siphashkey.clone();
+				image.siphashkey = image_r.as_ref().unwrap().siphashkey;
+				image.naming = image_r.as_ref().unwrap().naming;
+				image.name_key = image_r.as_ref().unwrap().name_key.clone();
+				image.generation = image_r.as_ref().unwrap().generation;
+				image.adopt_tombstones(image_r.as_ref().unwrap());
+				image.register(&device);
 				image_l = Some(image);
 
 			}
 			Goal::Update => {
 				Jambon::gpull(&gpath, &config.command_pull)?;
-				image_l = Some(Image::from_local()?);
-				image_r = Some(Image::from_remote(&gpath, &key)?);
-				if image_l.as_ref().unwrap().siphashkey != image_r.as_ref().unwrap().siphashkey {
-					println!("Problem: Siphashkey (for hashing the filenames) differs between remote and local. \
+				kdf = Jambon::remote_kdf(&gpath)?;
+				keyring = config.keyring(kdf.as_ref(), false)?;
+				Jambon::check_remote_key(&gpath, &keyring)?;
+				image_l = Some(Image::from_local(&Image::local_file(), local_key.keyring(&keyring).as_ref())?);
+				image_r = Some(Image::from_remote(&gpath, &keyring)?);
+				image_r.as_ref().unwrap().check_generation(&Image::generation_file())?;
+				image_l.as_mut().unwrap().generation = image_r.as_ref().unwrap().generation + 1;
+				let (local, remote) = (image_l.as_ref().unwrap(), image_r.as_ref().unwrap());
+				if local.siphashkey != remote.siphashkey || local.naming != remote.naming {
+					println!("Problem: Siphashkey or naming (for hashing the filenames) differs between remote and local. \
 						Probably because you did blindpush, which regenerates the siphashkey. \
 						Do you want to continue with the new remote siphashkey? This is recommended. [Y/n]");
 					let mut buf = String::new();
 					io::stdin().read_line(&mut buf)?;
 					if (buf == "y\n") || (buf == "Y\n") || (buf == "\n") {
 						println!("updating local siphashkey with the remote one");
-						image_l.as_mut().unwrap().update_siphashkey(&image_r.as_ref().unwrap().siphashkey);
+						image_l.as_mut().unwrap().update_naming(image_r.as_ref().unwrap(), &keyring)?;
 					} else if (buf == "n\n") || (buf == "N\n") {
 						return Err("not really an error. Just exiting.".into());
 					} else {
@@ -388,127 +1999,456 @@ impl Jambon {
 						return Err("not really an error. Just exiting".into());
 					}
 				}
+				image_l.as_mut().unwrap().adopt_blobs(image_r.as_ref().unwrap());
+				image_l.as_mut().unwrap().adopt_tombstones(image_r.as_ref().unwrap());
+			}
+			Goal::Rekey => {
+				Jambon::gpull(&gpath, &config.command_pull)?;
+				kdf = Jambon::remote_kdf(&gpath)?;
+				keyring = config.keyring(kdf.as_ref(), false)?;
+				Jambon::check_remote_key(&gpath, &keyring)?;
+				image_l = Some(Image::from_local(&Image::local_file(), local_key.keyring(&keyring).as_ref())?);
+				let mut image = Image::from_remote(&gpath, &keyring)?;
+				image.check_generation(&Image::generation_file())?;
+				image.generation += 1;
+				image_r = Some(image);
+				new_key = Some(PendingKey::load_or_create(&config)?);
+			}
+			Goal::Recover => {
+				Jambon::gpull(&gpath, &config.command_pull)?;
+				kdf = match Jambon::remote_kdf(&gpath) {
+					Ok(Some(kdf)) => Some(kdf),
+					_ => Jambon::blobs_kdf(&gpath)?,
+				};
+				keyring = config.keyring(kdf.as_ref(), false)?;
+				image_l = Image::from_local(&Image::local_file(), local_key.keyring(&keyring).as_ref()).ok();
+				image_r = None;
 			}
 		}
+		for image in image_l.iter_mut().chain(image_r.iter_mut()) {
+			image.pin_namekey(&keyring);
+		}
 		let jambon = Jambon {
-			image_l: image_l,
-			image_r: image_r,
-			gpath: gpath,
-			key: key,
+			image_l,
+			image_r,
+			gpath,
+			keyring,
+			kdf,
+			format: config.blob_format(),
+			new_key,
+			local_key,
+			device,
+			conflicts,
 			command_push: config.command_push,
 			//command_pull: config.command_pull,
 			did_something: false,
+			failed: Vec::new(),
 		};
 		Ok(jambon)
 	}
 
+	/// id of this device from .sync/. A random one is created the first time.
+	fn device_id() -> Result<String> {
+		let path: PathBuf = [FOLDER_SYNC, DEVICE].iter().collect();
+		match fs::read_to_string(&path) {
+			Ok(string) => Ok(string.trim().to_string()),
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+				let mut id = [0; 8];
+				rand_bytes(&mut id)?;
+				let id = slice_to_hex(&id);
+				fs::write(&path, format!("{}\n", id))?;
+				Ok(id)
+			}
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// read the parameters of the key derivation from the header of the remote image
+	fn remote_kdf(gpath: &Path) -> Result<Option<KdfParams>> {
+		match Image::remote_header(gpath)? {
+			Some(header) => header.kdf(),
+			None => Ok(None),
+		}
+	}
+
+	/// read the parameters of the key derivation from the headers of the blobs, for when the remote image is lost
+	fn blobs_kdf(gpath: &Path) -> Result<Option<KdfParams>> {
+		for path in get_filenames(&gpath.to_path_buf()) {
+			if let Ok((header, _)) = Header::from_bytes(&readfile(&path)?) {
+				if let Some(kdf) = header.kdf()? {
+					return Ok(Some(kdf));
+				}
+			}
+		}
+		Ok(None)
+	}
+
+	/// make sure the remote image is encrypted with the key of this device. If another device ran
+	/// `symsync rekey` or the key is wrong, compare the fingerprints instead of failing on decryption.
+	/// Images written by older versions have no key check value and are only checked by the key id.
+	fn check_remote_key(gpath: &Path, keyring: &Keyring) -> Result<()> {
+		let header = match Image::remote_header(gpath)? {
+			Some(header) => header,
+			None => return Ok(()),
+		};
+		let key = keyring.get(&header.key_id);
+		let check = header.key_check()?;
+		if let (Some(key), Some(check)) = (key, check) {
+			if !memcmp::eq(&key_check(key), check) {
+				return Err("the key of this device has the id of the key of the remote folder, but does not match it".into());
+			}
+		}
+		if key.is_none() {
+			println!("The key of this device does not match the key of the remote folder.");
+			match check {
+				Some(check) => println!("    remote folder: {}", fingerprint(check)),
+				None => println!("    remote folder: key id {}", slice_to_hex(&header.key_id)),
+			}
+			for key in keyring.candidates() {
+				println!("    this device:   {}", fingerprint(&key_check(key)));
+			}
+			println!("If another device ran `symsync rekey` or activated a new key, copy that key into \
+				{}/{} (or use the new passphrase) and run this command again. `symsync key fingerprint` \
+				shows the fingerprints of a device.", FOLDER_SYNC, CONFIG_FILE);
+			keyring.for_header(&header)?;
+		}
+		Ok(())
+	}
+
 	/// encrypt file, save it to `remote` and add entry to `image_l`
 	pub fn encrypt_save_add(&mut self, fname: &Path) -> Result<&mut Self> {
-		let content = readfile(&fname)?;
-		let (cipher, iv) = my_encrypt(&content, &self.key)?;
 		let image = self.image_l.as_mut().ok_or("local image is none")?;
-		let getidx = image.get_index(&fname);
-		let namehash;
-		match getidx {
+		let mut meta = Image::metadata(fname, image.blobname(fname, &self.keyring)?)?;
+		// a file that was created again continues the version of the deleted one, such that it is not
+		// taken for a concurrent change on devices that still have that
+		if let Some(idx) = image.get_index(fname) {
+			meta.version = image.filesystem[idx].version.clone();
+		} else if let Some(tombstone) = image.tombstone(fname) {
+			meta.version = tombstone.version.clone();
+		}
+		// the pushed version replaces the remote one, so it has seen it
+		if let Some(remote) = self.image_r.as_ref().and_then(|image_r| image_r.get_index(fname).map(|idx| &image_r.filesystem[idx])) {
+			merge_versions(&mut meta.version, &remote.version);
+		}
+		*meta.version.entry(self.device.clone()).or_insert(0) += 1;
+		meta.written_by = Some(self.device.clone());
+		let store = BlobStore::new(&self.gpath, &image.packs, &self.keyring);
+		write_pieces(myopen(fname)?, &store, &mut meta, self.keyring.active(), self.kdf.as_ref(), &self.format)?;
+		image.unpack(&meta.namehash);
+		match image.get_index(fname) {
 			Some(idx) => {
-				image.update(&fname, &content, iv, idx)?;
-				namehash = &image.filesystem[idx].namehash;
+				image.update(meta, idx);
 			}
 			None => {
-				image.push(&fname, &content, iv)?;
-				namehash = &image.filesystem.last().unwrap().namehash;
-				}
+				image.push(meta);
+			}
 		}
-		let mut path = PathBuf::from(&self.gpath);
-		path.push(namehash);
-		writefile(&path, &cipher)?;
+		// the file was created again
+		image.tombstones.retain(|tombstone| Path::new(&tombstone.name) != fname);
 		Ok(self)
 	}
 
-	/// this function performs one of three actions on file fname based on its modification time
-	/// - action1: encrypt, save in `remote` and update `image_l`
-	/// - action2: decrypt, check signature, save in `local` and update `image_l`
-	/// - action3: delete file in `local` and delete entry in `image_l`
-	///
-	/// it tries to detect some possible conflicts but it is far from idiot proof
-	pub fn update(&mut self, fname: &Path) -> Result<&mut Self> {
-		let attr = fs::metadata(&fname)?;
-		let modtime = attr.modified().unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs();
-		let getidx_l = self.image_l.as_ref().unwrap().get_index(&fname);
-		let getidx_r = self.image_r.as_ref().unwrap().get_index(&fname);
-		match getidx_l {
-			Some(idx_l) => {
-				let modtime_l = self.image_l.as_ref().unwrap().filesystem[idx_l].modified;
-				let modtime_la = self.image_l.as_ref().unwrap().filesystem[idx_l].actually_modified;
-				match getidx_r {
-					Some(idx_r) => {
-						// file in both local and remote image
-						//let modtime_r = self.image_r.as_ref().unwrap().filesystem[idx_r].modified;
-						let modtime_ra = self.image_r.as_ref().unwrap().filesystem[idx_r].actually_modified;
-						if modtime > modtime_l { 
-							// file has been locally modified since the last pull or push
-							if modtime_la < modtime_ra {
-								println!("problem with {:?}: file was updated both locally and remotely", fname);
-                                let mut fname_backup = OsString::from(fname);
-                                fname_backup.push("_local_backup");
-							    fs::rename(&fname, &fname_backup)?;
-							    //println!("action2 (decrypt, save, update image entry {:?}", &fname);
-							    Self::decrypt_save_add(
-							    	self.image_l.as_mut().unwrap(),
-							    	&self.image_r.as_ref().unwrap().filesystem[idx_r],
-							    	&self.gpath,
-							    	&self.key,
-							    	&self.image_r.as_ref().unwrap().siphashkey)?;
-							    self.did_something = true;
-								println!("file {:?} was pulled and the local file backed up as {:?}", &fname, &fname_backup);
-								println!("please manually merge the two files and run update again!");
-							}
-                            else {
-							    println!("action1 (encrypt, save, update image entry) {:?}", &fname);
-							    self.encrypt_save_add(&fname)?;
-							    self.did_something = true;
-                            }
-						}
-						else if modtime_la < modtime_ra {
-							println!("action2 (decrypt, save, update image entry {:?}", &fname);
-							Self::decrypt_save_add(
-								self.image_l.as_mut().unwrap(),
-								&self.image_r.as_ref().unwrap().filesystem[idx_r],
-								&self.gpath,
-								&self.key,
-								&self.image_r.as_ref().unwrap().siphashkey)?;
-							self.did_something = true;
+	/// three-way sync of the local folder with the remote image. Every file in the local folder
+	/// (`fnames`), the local image and the remote image is compared with its entry in the local
+	/// image, which is the state of the last sync, and `decide` tells what to do with it.
+	pub fn sync(&mut self, fnames: &[PathBuf]) -> Result<&mut Self> {
+		let mut names = fnames.to_vec();
+		let mut seen: HashSet<PathBuf> = names.iter().cloned().collect();
+		for name in self.image_l.as_ref().unwrap().get_filenames().into_iter().chain(self.image_r.as_ref().unwrap().get_filenames()) {
+			if seen.insert(name.clone()) {
+				names.push(name);
+			}
+		}
+		for fname in &names {
+			let (image_l, image_r) = (self.image_l.as_ref().unwrap(), self.image_r.as_ref().unwrap());
+			let base = image_l.get_index(fname).map(|idx| &image_l.filesystem[idx]);
+			let remote = image_r.get_index(fname).map(|idx| image_r.filesystem[idx].clone());
+			let tombstone = image_r.tombstone(fname);
+			let (mut local, stat) = Jambon::local_side(fname, base)?;
+			if let (None, Some(tombstone), Side::Changed) = (base, tombstone, local) {
+				// never synced here: delete it only if it is the version that was deleted
+				if tombstone.hash.is_some() && tombstone.hash == Some(hash_file(fname)?) {
+					local = Side::Unchanged;
+				}
+			}
+			let mut remote_side = if remote.is_none() && tombstone.is_some() {Side::Deleted} else {Jambon::remote_side(remote.as_ref(), base)};
+			let versioned = |metadata: &&Metadata| !metadata.version.is_empty();
+			if let (Some(remote), Some(base)) = (remote.as_ref().filter(versioned), base.filter(versioned)) {
+				match compare_versions(&remote.version, &base.version) {
+					Some(Ordering::Less) => {
+						// the remote entry is older than the last sync here, e.g. after a blindpush of a
+						// device that was behind: push this version again
+						remote_side = Side::Unchanged;
+						if local == Side::Unchanged {
+							local = Side::Changed;
 						}
-
 					}
-					None => {
-						// file in local image only
-						if modtime < self.image_r.as_ref().unwrap().last_update {
-							println!("action3 (delete file and entry in local image {:?})", &fname);
-							fs::remove_file(&fname)?;
-							self.image_l.as_mut().unwrap().filesystem.remove(idx_l);
-							self.image_l.as_mut().unwrap().last_update = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-							self.did_something = true;
-						}
+					None if local == Side::Unchanged => {
+						// written concurrently with the version here, e.g. when two devices pushed at
+						// the same time and one remote image won: keep both
+						local = Side::Changed;
 					}
+					_ => {}
+				}
+			}
+			let mut decision = decide(base.is_some(), local, remote_side);
+			if local == Side::Unchanged && base.is_some() && stat != base.unwrap().stat {
+				// touched but not modified: remember the new stat, such that it is not hashed again
+				// after the next save
+				let idx = image_l.get_index(fname).unwrap();
+				self.image_l.as_mut().unwrap().filesystem[idx].stat = stat;
+			} else if decision == Decision::Conflict && remote.as_ref().and_then(|remote| remote.hash.as_ref()).is_some()
+					&& fname.exists() && remote.as_ref().unwrap().hash == Some(hash_file(fname)?) {
+				// both sides made the same change
+				self.adopt_remote(fname, remote.as_ref().unwrap(), stat)?;
+				decision = Decision::Nothing;
+			}
+			// the base of a file that failed stays as it is, such that it is tried again at the next sync
+			if let Err(e) = self.apply(fname, decision, remote.as_ref()) {
+				println!("could not sync {:?}: {}", fname, e);
+				self.failed.push((fname.clone(), remote));
+			}
+		}
+		if !self.failed.is_empty() {
+			println!("{} files could not be synced, they are tried again at the next update", self.failed.len());
+		}
+		if self.image_l.as_mut().unwrap().register(&self.device) {
+			self.did_something = true;
+		}
+		Ok(self)
+	}
+
+	/// State of the local file compared with its base, and its stat. If the stat is the same as at
+	/// the last sync, the file is unchanged. Otherwise it is hashed and compared with the content
+	/// hash. Entries of older versions have neither, their modification time is compared instead.
+	fn local_side(fname: &Path, base: Option<&Metadata>) -> Result<(Side, Option<Stat>)> {
+		let attr = match fs::metadata(fname) {
+			Ok(attr) => attr,
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((Side::Absent, None)),
+			Err(e) => return Err(e.into()),
+		};
+		let stat = Stat::of(&attr)?;
+		let base = match base {
+			Some(base) => base,
+			None => return Ok((Side::Changed, Some(stat))),
+		};
+		let unchanged = if base.stat.as_ref() == Some(&stat) {
+			true
+		} else if let Some(hash) = &base.hash {
+			*hash == hash_file(fname)?
+		} else {
+			base.modified == attr.modified()?.duration_since(UNIX_EPOCH)?.as_secs()
+		};
+		Ok((if unchanged {Side::Unchanged} else {Side::Changed}, Some(stat)))
+	}
+
+	/// state of the remote entry compared with the base. The version of a file is told by its version
+	/// vector, or for entries of older versions by the time it was actually modified. Both are kept by
+	/// `rekey`, unlike the signature.
+	fn remote_side(remote: Option<&Metadata>, base: Option<&Metadata>) -> Side {
+		match (remote, base) {
+			(None, _) => Side::Absent,
+			(Some(remote), Some(base)) if remote.same_version(base) => Side::Unchanged,
+			(Some(_), _) => Side::Changed,
+		}
+	}
+
+	/// take the remote entry of `fname` as the base, as the local file has the same content
+	fn adopt_remote(&mut self, fname: &Path, remote: &Metadata, stat: Option<Stat>) -> Result<()> {
+		let mut metadata = remote.clone();
+		metadata.modified = fs::metadata(fname)?.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+		metadata.stat = stat;
+		let image = self.image_l.as_mut().unwrap();
+		match image.get_index(fname) {
+			Some(idx) => image.filesystem[idx] = metadata,
+			None => image.filesystem.push(metadata),
+		}
+		self.did_something = true;
+		Ok(())
+	}
+
+	/// carry out the decision for `fname`. `remote` is its entry in the remote image.
+	fn apply(&mut self, fname: &Path, decision: Decision, remote: Option<&Metadata>) -> Result<()> {
+		match decision {
+			Decision::Nothing => return Ok(()),
+			Decision::Push => {
+				println!("push {:?}", fname);
+				self.encrypt_save_add(fname)?;
+			}
+			Decision::Pull => {
+				println!("pull {:?}", fname);
+				self.pull(remote.ok_or("nothing to pull")?)?;
+			}
+			Decision::DeleteLocal => {
+				match self.image_r.as_ref().unwrap().tombstone(fname) {
+					Some(tombstone) => println!("delete {:?}, it was deleted by device {}", fname, tombstone.deleted_by),
+					None => println!("delete {:?}, it was deleted remotely", fname),
 				}
+				fs::remove_file(fname)?;
+				self.forget(fname);
+			}
+			Decision::DeleteRemote => {
+				println!("delete {:?} remotely, it was deleted locally", fname);
+				let image = self.image_l.as_mut().unwrap();
+				let base = image.get_index(fname).map(|idx| &image.filesystem[idx]);
+				let (hash, version) = (base.and_then(|base| base.hash.clone()), base.map(|base| base.version.clone()).unwrap_or_default());
+				image.tombstones.push(Tombstone {
+					name: fname.to_str().ok_or("file name is not UTF-8")?.to_string(),
+					hash,
+					deleted_by: self.device.clone(),
+					deleted_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+					seen_by: Vec::new(),
+					version,
+				});
+				self.forget(fname);
+			}
+			Decision::Forget => self.forget(fname),
+			Decision::Conflict => self.resolve_conflict(fname, remote)?,
+		}
+		self.did_something = true;
+		Ok(())
+	}
+
+	/// Resolve a file that changed on both sides with the conflict policy of the file. If one side
+	/// deleted the file, the version of the other side is kept. The outcome is logged.
+	fn resolve_conflict(&mut self, fname: &Path, remote: Option<&Metadata>) -> Result<()> {
+		let remote = match remote {
+			Some(remote) if fname.exists() => remote,
+			Some(remote) => {
+				println!("problem with {:?}: file was deleted locally but changed remotely. Pulling it again.", fname);
+				self.pull(remote)?;
+				return self.log_conflict(fname, "deleted here but changed remotely, pulled it again");
 			}
 			None => {
-				match getidx_r {
-					Some(_idx_r) => {
-						// file in remote image only
-						println!("problem with {:?}: file was created locally even though it existed remotely", &fname);
-					}
-					None => {
-						// file not in images
-						println!("action1 (encrypt, save, update image entry) {:?}", &fname);
-						self.encrypt_save_add(&fname)?;
-						self.did_something = true;
-					}
+				println!("problem with {:?}: file was deleted remotely but changed locally. Pushing it again.", fname);
+				self.encrypt_save_add(fname)?;
+				return self.log_conflict(fname, "deleted remotely but changed here, pushed it again");
+			}
+		};
+		let by = remote.written_by.as_ref().map(|device| format!(" by device {}", device)).unwrap_or_default();
+		println!("problem with {:?}: file was changed both locally and remotely{}", fname, by);
+		let policy = self.conflicts.for_file(fname).clone();
+		let outcome = match &policy {
+			ConflictPolicy::KeepBoth => self.keep_both(fname, remote)?,
+			ConflictPolicy::Local => {
+				self.encrypt_save_add(fname)?;
+				"pushed the local version".to_string()
+			}
+			ConflictPolicy::Remote => {
+				self.pull(remote)?;
+				"pulled the remote version".to_string()
+			}
+			ConflictPolicy::Newest => {
+				if fs::metadata(fname)?.modified()?.duration_since(UNIX_EPOCH)?.as_secs() >= remote.actually_modified {
+					self.encrypt_save_add(fname)?;
+					"pushed the local version, it is newer".to_string()
+				} else {
+					self.pull(remote)?;
+					"pulled the remote version, it is newer".to_string()
 				}
 			}
-		}		
-		Ok(self)
+			ConflictPolicy::Merge(command) => self.merge(fname, remote, command)?,
+		};
+		println!("{:?}: {}", fname, outcome);
+		if policy == ConflictPolicy::KeepBoth {
+			println!("please manually merge the two files and run update again!");
+		}
+		self.log_conflict(fname, &format!("changed here and remotely{}, {}", by, outcome))
+	}
+
+	/// rename the local version of `fname` with the id of this device and the time and pull the remote one
+	fn keep_both(&mut self, fname: &Path, remote: &Metadata) -> Result<String> {
+		let fname_backup = self.conflict_name(fname);
+		fs::rename(fname, &fname_backup)?;
+		if let Err(e) = self.pull(remote) {
+			fs::rename(&fname_backup, fname)?;
+			return Err(e);
+		}
+		Ok(format!("pulled the remote version and kept the local one as {:?}", fname_backup))
+	}
+
+	/// run the merge tool `command` on both versions of `fname`. The remote version is pulled into
+	/// .sync/ for it and the tool works on a copy of the local one, which replaces `fname` only if
+	/// the tool succeeds. If it fails, both versions are kept.
+	fn merge(&mut self, fname: &Path, remote: &Metadata, command: &str) -> Result<String> {
+		let file_name = fname.file_name().ok_or("no file name")?.to_str().ok_or("file name is not UTF-8")?;
+		let local_tmp: PathBuf = [FOLDER_SYNC, &format!("local_{}", file_name)].iter().collect();
+		let remote_tmp: PathBuf = [FOLDER_SYNC, &format!("remote_{}", file_name)].iter().collect();
+		let merged_tmp: PathBuf = [FOLDER_SYNC, &format!("merged_{}", file_name)].iter().collect();
+		fs::rename(fname, &local_tmp)?;
+		if let Err(e) = self.pull(remote) {
+			fs::rename(&local_tmp, fname)?;
+			return Err(e);
+		}
+		fs::rename(fname, &remote_tmp)?;
+		fs::rename(&local_tmp, fname)?;
+		fs::copy(fname, &merged_tmp)?;
+		let args: Vec<String> = command.split_whitespace()
+			.map(|arg| arg.replace("{local}", merged_tmp.to_str().unwrap()).replace("{remote}", remote_tmp.to_str().unwrap()))
+			.collect();
+		let status = Command::new(&args[0]).args(&args[1..]).status();
+		if let Ok(status) = &status {
+			if status.success() {
+				fs::rename(&merged_tmp, fname)?;
+				fs::remove_file(&remote_tmp)?;
+				self.encrypt_save_add(fname)?;
+				return Ok(format!("merged with {:?} and pushed the result", args[0]));
+			}
+		}
+		fs::remove_file(&merged_tmp)?;
+		let fname_backup = self.conflict_name(fname);
+		fs::rename(fname, &fname_backup)?;
+		fs::rename(&remote_tmp, fname)?;
+		let failure = match status {
+			Ok(status) => status.to_string(),
+			Err(e) => e.to_string(),
+		};
+		Ok(format!("merging with {:?} failed ({}), pulled the remote version and kept the local one as {:?}",
+			args[0], failure, fname_backup))
+	}
+
+	/// name for the local version of `fname` in a conflict: `<stem>_conflict_<device>_<time>.<extension>`
+	fn conflict_name(&self, fname: &Path) -> PathBuf {
+		let stem = fname.file_stem().unwrap_or_default().to_string_lossy();
+		let extension = fname.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+		let time: String = format_utc(SystemTime::now()).chars().filter(char::is_ascii_alphanumeric).collect();
+		let mut n = 1;
+		loop {
+			let suffix = if n == 1 {String::new()} else {format!("_{}", n)};
+			let candidate = fname.with_file_name(format!("{}_conflict_{}_{}{}{}", stem, self.device, time, suffix, extension));
+			if !candidate.exists() {
+				return candidate;
+			}
+			n += 1;
+		}
+	}
+
+	/// append the outcome of a conflict to .sync/conflicts.log
+	fn log_conflict(&self, fname: &Path, outcome: &str) -> Result<()> {
+		let path: PathBuf = [FOLDER_SYNC, CONFLICTS].iter().collect();
+		let mut log = fs::OpenOptions::new().create(true).append(true).open(path)?;
+		writeln!(log, "{} device {} {:?}: {}", format_utc(SystemTime::now()), self.device, fname, outcome)?;
+		Ok(())
+	}
+
+	/// decrypt the file of the remote entry `metadata` into the local folder and add it to `image_l`
+	fn pull(&mut self, metadata: &Metadata) -> Result<()> {
+		let image_r = self.image_r.as_ref().unwrap();
+		Self::decrypt_save_add(
+			self.image_l.as_mut().unwrap(),
+			metadata,
+			&mut BlobStore::new(&self.gpath, &image_r.packs, &self.keyring),
+			image_r.siphashkey,
+			image_r.naming).map_err(|e| format!("could not pull {:?}: {}", metadata.name, e).into())
+	}
+
+	/// remove the entry of `fname` from `image_l`
+	fn forget(&mut self, fname: &Path) {
+		let image = self.image_l.as_mut().unwrap();
+		if let Some(idx) = image.get_index(fname) {
+			image.filesystem.remove(idx);
+			image.last_update = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+		}
 	}
 
 	/// if files are in remote and not in local:
@@ -516,16 +2456,16 @@ impl Jambon {
 	pub fn load_missing(&mut self) -> Result<&mut Self> {
 		let fsystem_r = &self.image_r.as_ref().unwrap().filesystem;
 		let fnames_l = self.image_l.as_ref().unwrap().get_filenames();
-		for i in 0..fsystem_r.len() {
-			let fname_r = &fsystem_r[i].name;
-			if fnames_l.iter().find(|&fname| fname_r==fname.to_str().unwrap()).is_none() {
+		for metadata_r in fsystem_r {
+			let fname_r = &metadata_r.name;
+			if !fnames_l.iter().any(|fname| fname_r==fname.to_str().unwrap()) {
 				println!("load missing; action2 (decrypt, save, add) file {:?}", &fname_r);
 				match Self::decrypt_save_add(
 					self.image_l.as_mut().unwrap(),
-					&fsystem_r[i],
-					&self.gpath,
-					&self.key,
-					&self.image_r.as_ref().unwrap().siphashkey) {
+					metadata_r,
+					&mut BlobStore::new(&self.gpath, &self.image_r.as_ref().unwrap().packs, &self.keyring),
+					self.image_r.as_ref().unwrap().siphashkey,
+					self.image_r.as_ref().unwrap().naming) {
                     
                     Ok(_) => {}
                     Err(e) => {println!("WARNING: Could not load file. Error message: {:?}! Coninuing.", e)}
@@ -536,98 +2476,320 @@ impl Jambon {
 		Ok(self)
 	}
 
-	/// if a file is not in local but in local image:
-	/// - action4: delete entry in local image
-	pub fn clean_image(&mut self, fnames: &Vec<PathBuf>) -> Result<&mut Self> {
-		let fnames_l = self.image_l.as_ref().unwrap().get_filenames();
-		for fname_l in fnames_l {
-			if fnames.iter().find(|&fname| &fname_l==fname).is_none() {
-				println!("action4 (remove from image) file {:?}", &fname_l);
-				let idx = self.image_l.as_ref().unwrap().get_index(&fname_l).unwrap();
-				self.image_l.as_mut().unwrap().filesystem.remove(idx);
-				self.image_l.as_mut().unwrap().last_update = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-				self.did_something = true;
+	/// if after update files are in remote but not in local image:
+	/// - action5: delete file from remote
+	pub fn clean_remote(&self) -> Result<&Self> {
+		self.remove_unused_blobs(self.image_l.as_ref().unwrap())
+	}
+
+	/// removes the blobs in gpath that are not referenced by any entry of `image`, or that are in a pack.
+	/// This includes the `*.tmp` files left by interrupted writes.
+	fn remove_unused_blobs(&self, image: &Image) -> Result<&Self> {
+		let fnames_r = get_filenames(&self.gpath);
+		let referenced = image.referenced();
+		let packed = image.packed();
+		let packs: HashSet<&str> = image.packs.iter().map(|pack| pack.name.as_str())
+			.chain(image.decoys.iter().map(String::as_str)).collect();
+		for fname_r in fnames_r {
+			if image.quarantine.iter().any(|name| fname_r.file_name().unwrap() == name.as_str()) {
+				println!("keeping blob {:?}, it is in the quarantine of the remote image", &fname_r);
+				continue;
+			}
+			let name = fname_r.file_name().unwrap().to_string_lossy();
+			if (!referenced.contains(name.as_ref()) || packed.contains(name.as_ref()))
+				&& !packs.contains(name.as_ref()) && name != IMAGE_REMOTE {
+				println!("action5 (deleting file from remote) file {:?}", &fname_r);
+				fs::remove_file(&fname_r)?;
+			}
+		}
+		Ok(self)
+	}
+
+	/// decrypt every blob in the remote image with the old key and encrypt it with the new one.
+	/// Blobs that already have the new key (from an interrupted rekey) are only checked.
+	/// The entries of `image_l` are updated to match the new blobs.
+	pub fn rekey(&mut self) -> Result<&mut Self> {
+		let (new_key, new_kdf) = self.new_key.as_ref().ok_or("no new key for rekey")?;
+		let image_r = self.image_r.as_mut().unwrap();
+		let siphashkey = image_r.siphashkey;
+		let naming = image_r.naming;
+		let mut store = BlobStore::new(&self.gpath, &image_r.packs, &self.keyring);
+		let mut rewritten = Vec::new();
+		for metadata in &mut image_r.filesystem {
+			let mut new_metadata = metadata.clone();
+			// encrypted names are renamed, such that they can be decrypted with the new key
+			if naming == Naming::Siv {
+				new_metadata.namehash = encrypt_name(&metadata.name, new_key)?;
+			}
+			// an interrupted rekey may already have renamed the blob
+			let current = if new_metadata.namehash != metadata.namehash && store.contains(&new_metadata.namehash) {
+				new_metadata.namehash.clone()
+			} else {
+				metadata.namehash.clone()
+			};
+			let done = metadata.algorithm != Algorithm::Aes256Cbc && store.header(&current)
+				.is_ok_and(|header| header.key_id == key_id(new_key));
+			println!("rekey {:?}", &metadata.name);
+			new_metadata.algorithm = Algorithm::Aes256GcmStream;
+			new_metadata.signature_type = SignatureType::HmacSha3;
+			new_metadata.iv = None;
+			if done {
+				let blob = store.read(&current)?;
+				let new_keyring = Keyring::single(new_key.clone());
+				let message = my_decrypt(&blob, &new_keyring, &new_metadata.aad())?;
+				new_metadata.pieces = match Header::from_bytes(&blob)?.0.metadata(&new_keyring)? {
+					Some(embedded) if embedded.pieces => Some(serde_json::from_slice(&message)?),
+					_ => None,
+				};
+				new_metadata.signature = slice_to_hex(&calc_mac(&blob, new_key));
+			} else {
+				let content: PathBuf = [FOLDER_SYNC, REKEY_TMP].iter().collect();
+				open_blob_file(&mut store, metadata, siphashkey, &content)?;
+				write_pieces(myopen(&content)?, &store, &mut new_metadata, new_key, new_kdf.as_ref(), &self.format)?;
+				fs::remove_file(&content)?;
+				rewritten.push(new_metadata.namehash.clone());
 			}
+			let path = self.gpath.join(&metadata.namehash);
+			if new_metadata.namehash != metadata.namehash && path.exists() {
+				fs::remove_file(&path)?;
+			}
+			*metadata = new_metadata;
+		}
+		for name in rewritten {
+			image_r.unpack(&name);
 		}
+		if naming == Naming::Siv {
+			image_r.name_key = Some(slice_to_hex(&key_id(new_key)));
+		}
+		self.image_l.as_mut().unwrap().adopt_blobs(self.image_r.as_ref().unwrap());
+		self.did_something = true;
 		Ok(self)
 	}
 
-	/// if after update files are in remote but not in local image:
-	/// - action5: delete file from remote
-	pub fn clean_remote(&self) -> Result<&Self> {
-		let fnames_r = get_filenames(&self.gpath);
-		let fnames_l = self.image_l.as_ref().unwrap().get_hashnames();
-		for fname_r in fnames_r {
-			if fnames_l.iter().find(|&fname_l| &fname_r.file_name().unwrap()==fname_l).is_none() {
-				if fname_r.file_name().unwrap() != "image" {
-					println!("action5 (deleting file from remote) file {:?}", &fname_r);
-					fs::remove_file(&fname_r)?;
+	/// rebuild the remote image from the metadata embedded in the blobs, or from the local image for blobs
+	/// written by older versions. Blobs that cannot be attributed to a file are kept in the quarantine of the remote image,
+	/// such that no device deletes them, and listed in .sync/quarantine.json.
+	/// Of several blobs for the same file the newest one is taken.
+	pub fn recover(&mut self) -> Result<&mut Self> {
+		let mut image = Image::new();
+		image.generation = Image::seen_generation(&Image::generation_file())? + 1;
+		let mut quarantine = Vec::new();
+		let mut pieces = Vec::new();
+		let mut attributed = HashSet::new();
+		let mut siv = true;
+		let mut packs = Vec::new();
+		// the pack of every blob that is taken from a pack, None for blobs that are taken from their own file
+		let mut location: HashMap<String, Option<String>> = HashMap::new();
+		let purpose = |header: Header| header.subkey().ok().flatten().map(|(purpose, _)| purpose);
+		for path in get_filenames(&self.gpath) {
+			let blobname = path.file_name().unwrap().to_string_lossy().to_string();
+			if blobname == IMAGE_REMOTE {
+				continue;
+			}
+			// left by an interrupted write
+			if blobname.ends_with(".tmp") {
+				fs::remove_file(&path)?;
+				continue;
+			}
+			// pieces are attributed by the files they belong to
+			let blobs = match Header::read_from(&mut myopen(&path)?).ok().and_then(|(header, _)| purpose(header)) {
+				Some(Purpose::Piece) => {
+					location.insert(blobname.clone(), None);
+					pieces.push(blobname);
+					continue;
+				}
+				Some(Purpose::Pack) => match open_pack(&readfile(&path)?, &blobname, &self.keyring) {
+					Ok(blobs) if blobs.is_empty() => {
+						image.decoys.push(blobname);
+						continue;
+					}
+					Ok(blobs) => {
+						let content = blobs.iter().map(|(name, blob)| (name.clone(), blob.len() as u32)).collect();
+						packs.push(Pack { name: blobname.clone(), blobs: content });
+						blobs.into_iter().map(|(name, blob)| (name, blob, Some(blobname.clone()))).collect()
+					}
+					Err(e) => {
+						println!("could not recover {:?}: {}", &path, e);
+						quarantine.push(blobname);
+						continue;
+					}
+				},
+				_ => vec![(blobname, readfile(&path)?, None)],
+			};
+			for (blobname, blob, pack) in blobs {
+				if Header::from_bytes(&blob).ok().and_then(|(header, _)| purpose(header)) == Some(Purpose::Piece) {
+					location.entry(blobname.clone()).or_insert(pack);
+					pieces.push(blobname);
+					continue;
+				}
+				// blobs without embedded metadata can still be attributed with the local image
+				let recovered = recover_blob(&blob, &blobname, &self.keyring).or_else(|e| -> Result<Metadata> {
+					let local = self.image_l.as_ref().ok_or(e)?;
+					let metadata = local.filesystem.iter().find(|metadata| metadata.namehash == blobname)
+						.ok_or("the blob was written by an older version and is not in the local image")?;
+					open_blob(&blob, metadata, &self.keyring, local.siphashkey)?;
+					Ok(metadata.clone())
+				});
+				match recovered {
+					Ok(metadata) => {
+						println!("recovered {:?}", &metadata.name);
+						attributed.extend(metadata.pieces.iter().flatten().cloned());
+						let namer = self.keyring.candidates().into_iter()
+							.find(|key| encrypt_name(&metadata.name, key).ok().as_ref() == Some(&blobname));
+						siv &= namer.is_some();
+						if let (None, Some(key)) = (&image.name_key, namer) {
+							image.name_key = Some(slice_to_hex(&key_id(key)));
+						}
+						match image.get_index(Path::new(&metadata.name)) {
+							Some(idx) => {
+								if image.filesystem[idx].actually_modified < metadata.actually_modified {
+									image.update(metadata, idx);
+									location.insert(blobname, pack);
+								}
+							}
+							None => {
+								image.push(metadata);
+								location.insert(blobname, pack);
+							}
+						}
+					}
+					Err(e) => {
+						println!("could not recover {:?} from {:?}: {}", &blobname, &path, e);
+						quarantine.push(blobname);
+					}
 				}
 			}
 		}
+		// blobs written by older versions are named with the random siphashkey of the local image
+		if let Some(local) = &self.image_l {
+			image.siphashkey = local.siphashkey;
+			image.naming = local.naming;
+		}
+		image.pin_namekey(&self.keyring);
+		if !image.filesystem.is_empty() {
+			image.naming = if siv {Naming::Siv} else {Naming::Siphash};
+		}
+		quarantine.extend(pieces.into_iter().filter(|piece| !attributed.contains(piece)));
+		// a pack keeps the blobs that are read from it and those that could not be attributed
+		for mut pack in packs {
+			let here = Some(pack.name.clone());
+			pack.blobs.retain(|(name, _)| location.get(name) == Some(&here) || quarantine.contains(name));
+			if !pack.blobs.is_empty() {
+				image.packs.push(pack);
+			}
+		}
+		let path: PathBuf = [FOLDER_SYNC, QUARANTINE].iter().collect();
+		fs::write(path, serde_json::to_string(&quarantine)?)?;
+		if !quarantine.is_empty() {
+			println!("{} blobs could not be recovered. They are listed in {}/{} and will not be deleted.",
+				quarantine.len(), FOLDER_SYNC, QUARANTINE);
+		}
+		image.quarantine = quarantine;
+		self.image_r = Some(image);
+		self.did_something = true;
 		Ok(self)
 	}
 
-	/// save image files to local and remote and run `command_push`
-	pub fn finish(&self, goal: &Goal) -> Result<()> {
+	/// pack the blobs, save image files to local and remote and run `command_push`
+	pub fn finish(&mut self, goal: &Goal) -> Result<()> {
 		match goal {
 			Goal::BlindPush => {
-				self.image_l.as_ref().unwrap().save_local()?;
-				self.image_l.as_ref().unwrap().save_remote(&self.gpath, &self.key)?;
+				self.image_l.as_mut().unwrap().pack(&self.gpath, &self.keyring, self.keyring.active(), self.kdf.as_ref(), &self.format)?;
+				self.image_l.as_ref().unwrap().save_local(self.local_key.keyring(&self.keyring).as_ref())?;
+				self.image_l.as_ref().unwrap().save_remote(&self.gpath, self.keyring.active(), self.kdf.as_ref(), self.format.padding)?;
+				self.clean_remote()?;
 				self.gpush()?;
 			}
 			Goal::BlindPull => { 
-				self.image_l.as_ref().unwrap().save_local()?;
+				self.image_l.as_ref().unwrap().save_local(self.local_key.keyring(&self.keyring).as_ref())?;
 			}
 			Goal::Update => {
 				if self.did_something {
-					self.image_l.as_ref().unwrap().save_local()?;
-					self.image_l.as_ref().unwrap().save_remote(&self.gpath, &self.key)?;
+					// the remote image keeps the remote entries of the files that could not be synced,
+					// the local image their bases
+					let image = self.image_l.as_mut().unwrap();
+					let bases: Vec<_> = self.failed.iter().map(|(fname, remote)| image.set_entry(fname, remote.clone())).collect();
+					image.pack(&self.gpath, &self.keyring, self.keyring.active(), self.kdf.as_ref(), &self.format)?;
+					image.save_remote(&self.gpath, self.keyring.active(), self.kdf.as_ref(), self.format.padding)?;
 					self.clean_remote()?;
+					let image = self.image_l.as_mut().unwrap();
+					for ((fname, _), base) in self.failed.iter().zip(bases) {
+						image.set_entry(fname, base);
+					}
+					image.save_local(self.local_key.keyring(&self.keyring).as_ref())?;
 					self.gpush()?;
 				} else {
 					println!("nothing to be done");
 				}	
 			}
+			Goal::Rekey => {
+				let (new_key, new_kdf) = self.new_key.as_ref().ok_or("no new key for rekey")?;
+				// decoys with the old key would stand out
+				self.image_r.as_mut().unwrap().decoys.clear();
+				self.image_r.as_mut().unwrap().pack(&self.gpath, &self.keyring, new_key, new_kdf.as_ref(), &self.format)?;
+				self.image_l.as_mut().unwrap().adopt_blobs(self.image_r.as_ref().unwrap());
+				self.image_r.as_ref().unwrap().save_remote(&self.gpath, new_key, new_kdf.as_ref(), self.format.padding)?;
+				// the old key may be gone from the config file afterwards
+				self.image_l.as_ref().unwrap().save_local(self.local_key.keyring(&Keyring::single(new_key.clone())).as_ref())?;
+				// pieces encrypted with the old key are no longer referenced
+				self.remove_unused_blobs(self.image_r.as_ref().unwrap())?;
+				let config_path: PathBuf = [FOLDER_SYNC, CONFIG_FILE].iter().collect();
+				if new_kdf.is_none() {
+					Config::write_key(&config_path, new_key)?;
+				}
+				fs::remove_file(PendingKey::path())?;
+				self.gpush()?;
+				println!("The remote folder is now encrypted with key {}.", new_key);
+				if new_kdf.is_none() {
+					println!("The new key was written to {:?}. Copy it to all other devices.", &config_path);
+				} else {
+					println!("Use the new passphrase on all other devices.");
+				}
+			}
+			Goal::Recover => {
+				self.image_r.as_ref().unwrap().save_remote(&self.gpath, self.keyring.active(), self.kdf.as_ref(), self.format.padding)?;
+				self.gpush()?;
+				println!("The remote image was rebuilt. Run `symsync update` (or `symsync blindpull` on a new device).");
+			}
 		}
 		Ok(())
 		
 	}
 
 	/// action2
+	///
+	/// Throws a SigError if the blob does not match its entry in the image.
 	fn decrypt_save_add(
 		image_l: &mut Image, 
 		metadata: &Metadata, 
-		gpath: &Path, 
-		key: &Key,
-		siphashkey: &(u64, u64)) -> Result<()> {
+		store: &mut BlobStore,
+		siphashkey: Option<(u64, u64)>,
+		naming: Naming) -> Result<()> {
 		
-		let mut path = PathBuf::from(&gpath);
-		path.push(&metadata.namehash);
-		let content = readfile(&path)?;
-		let message = my_decrypt(&content, &key, &metadata.iv)?;
-		check_signature(&metadata.signature, &message, &siphashkey)?;
+		if naming == Naming::Siv && !store.keyring.candidates().into_iter()
+				.any(|key| encrypt_name(&metadata.name, key).ok().as_ref() == Some(&metadata.namehash)) {
+			return Err(SigError.into());
+		}
 		let path = PathBuf::from(&metadata.name);
 		let getidx = image_l.get_index(&path);
-		writefile(&path, &message)?;
+		open_blob_file(store, metadata, siphashkey, &path)?;
 		let attr = fs::metadata(&metadata.name)?;
-		let modtime = attr.modified().unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs();
+		let mut metadata = metadata.clone();
+		metadata.modified = attr.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+		metadata.stat = Some(Stat::of(&attr)?);
+		if metadata.hash.is_none() {
+			metadata.hash = Some(hash_file(&path)?);
+		}
 		match getidx {
-			Some(idx) => {
-				image_l.filesystem[idx] = metadata.clone();
-				image_l.filesystem[idx].modified = modtime;
-			}
-			None => {
-				image_l.filesystem.push(metadata.clone());
-				image_l.filesystem.last_mut().unwrap().modified = modtime;
-			}
+			Some(idx) => image_l.filesystem[idx] = metadata,
+			None => image_l.filesystem.push(metadata),
 		}
 		Ok(())
 	}
 
 	/// delete evything in gpath after user ok
 	fn cleangpath(gpath: &Path) -> Result<()> {
-		match fs::read_dir(&gpath) {
+		match fs::read_dir(gpath) {
 			Ok(mut iterator) => {
 				if iterator.next().is_some() {
 					// delete content
@@ -636,7 +2798,7 @@ impl Jambon {
 					io::stdin().read_line(&mut buf)?;
 					if (buf == "y\n") || (buf == "Y\n") || (buf == "\n") {
 						println!("deleting files in {:?}", &gpath);
-						fs::remove_dir_all(&gpath)?;
+						fs::remove_dir_all(gpath)?;
 					} else if (buf == "n\n") || (buf == "N\n") {
 						return Err("not really an error. Just exiting".into());
 					} else {
@@ -644,20 +2806,20 @@ impl Jambon {
 						return Err("not really an error. Just exiting".into());
 					}
 				}
-				return Ok(())
+				Ok(())
 			}
-			Err(_e) => {return Ok(())}
+			Err(_e) => {Ok(())}
 		}
 	}
 
 	/// run connand_pull
 	fn gpull(gpath: &Path, command_pull: &str) -> Result<()> {
 		let home = env::current_dir()?;
-		if let Err(_e) = env::set_current_dir(&gpath) {
+		if let Err(_e) = env::set_current_dir(gpath) {
 			println!("creating dir {:?}", &gpath);
-			fs::create_dir_all(&gpath)?;
+			fs::create_dir_all(gpath)?;
 		}
-		let command_pull = &command_pull.clone();
+		let command_pull = &command_pull;
         let command_iter = command_pull.split_whitespace();
         let mut args = Vec::new();
         for i in command_iter {
@@ -705,7 +2867,7 @@ impl Jambon {
 /// println!("these are all your files: {:?}", files);
 /// ```
 pub fn get_filenames(dir: &PathBuf) -> Vec<PathBuf> {
-	let entries = fs::read_dir(&dir).expect("error in get_filenames trying to read_dir");
+	let entries = fs::read_dir(dir).expect("error in get_filenames trying to read_dir");
 	let mut files = Vec::new();
 	for e in entries {
 		let path = e.unwrap().path();
@@ -713,129 +2875,1018 @@ pub fn get_filenames(dir: &PathBuf) -> Vec<PathBuf> {
 			if path.file_name().unwrap()==".sync" {
 				continue;
 			}
-			files.append(&mut get_filenames(&path));
-		} else {
-			files.push(path);
+			files.append(&mut get_filenames(&path));
+		} else {
+			files.push(path);
+		}
+	}
+    files.sort();
+    files
+}
+
+
+/// read a file and return its content
+fn readfile(fname: &Path)-> io::Result<Vec<u8>> {
+	let mut f = myopen(fname)?;
+	let mut buffer = Vec::new();
+	match f.read_to_end(&mut buffer){
+        Ok(_) => {Ok(buffer)}
+        Err(e) => {
+            println!("read_to_end in readfile failed");
+            Err(e)
+        }
+    }
+}
+
+/// create a file, and the folders it is in if necessary
+fn createfile(fname: &Path) -> io::Result<File> {
+	if let Some(folder) = fname.parent() {
+		fs::create_dir_all(folder)?;
+	}
+	File::create(fname)
+}
+
+/// write content to file
+fn writefile(fname: &Path, content: &[u8]) -> io::Result<()> {
+	match File::create(fname) {
+		Ok(mut file) => {
+			match file.write_all(content){
+                Ok(_) => {}
+                Err(e) => {println!("file.write_all failed in writefile. Error message: {:?}", e); return Err(e)}
+			}
+        }
+		Err(_e) => {
+			let folder = fname.parent().expect("error in writefile trying to get parent of fname");
+			match fs::create_dir_all(folder){
+                Ok(_) => {}
+                Err(e) => {println!("create_dir_all failed for folder {:?} in writefile. Error message: {:?}", &folder, e); return Err(e)}
+            }
+			match writefile(fname, content){
+                Ok(_) => {}
+                Err(e) => {println!("writefile failed in writefile. Error message: {:?}", e); return Err(e)}
+            }
+		}
+    }
+	Ok(())
+}
+
+
+
+
+/// identify a key without revealing it. The id is written into the header of every blob.
+fn key_id(key: &Key) -> KeyId {
+	let digest = hash(MessageDigest::sha3_256(), &[b"symsync key id".as_ref(), &key[..]].concat())
+		.expect("sha3_256 not available");
+	let mut id: KeyId = [0; L_KEY_ID];
+	id.copy_from_slice(&digest[..L_KEY_ID]);
+	id
+}
+
+/// key check value: a MAC of a constant with a subkey of `key`. Unlike the key id, it proves that
+/// the key is the same, and its beginning is shown as the fingerprint of the key.
+fn key_check(key: &Key) -> [u8; L_KEY_CHECK] {
+	let mut mac = Hmac::<Sha3_256>::new_varkey(&derive_key(key, Purpose::KeyCheck, b"")).unwrap();
+	mac.input(b"symsync key check");
+	let mut check = [0; L_KEY_CHECK];
+	check.copy_from_slice(&mac.result().code()[..L_KEY_CHECK]);
+	check
+}
+
+/// The key and a checksum in base32 (Crockford's alphabet, which ignores case and reads I, L and O
+/// as 1, 1 and 0), in groups of four digits, to be written down or read out to another device.
+fn key_to_code(key: &Key) -> SecretString {
+	let mut buf = [&key[..], &code_check(key)].concat();
+	let digits = SecretString(base32::encode(Alphabet::Crockford, &buf));
+	buf.zeroize();
+	let groups: Vec<&str> = digits.as_bytes().chunks(4).map(|group| std::str::from_utf8(group).unwrap()).collect();
+	SecretString(groups.join("-"))
+}
+
+/// parse a code from `key_to_code`. Spaces and dashes are ignored, a typo is caught by the checksum.
+fn code_to_key(code: &str) -> Result<Key> {
+	let digits = SecretString(code.chars().filter(|c| !c.is_whitespace() && *c != '-').collect());
+	let l_digits = (8 * (L_KEY + L_CODE_CHECK)).div_ceil(5);
+	if digits.chars().count() != l_digits {
+		return Err(format!("the key has to be {} base32 digits", l_digits).into());
+	}
+	let mut buf = base32::decode(Alphabet::Crockford, &digits).ok_or("the key contains a character that is not base32")?;
+	let mut key = Key::zero();
+	key.copy_from_slice(&buf[..L_KEY]);
+	let valid = memcmp::eq(&code_check(&key), &buf[L_KEY..]);
+	buf.zeroize();
+	if !valid {
+		return Err("the checksum of the key does not match. Is there a typo?".into());
+	}
+	Ok(key)
+}
+
+fn code_check(key: &Key) -> Vec<u8> {
+	let mut buf = [b"symsync key code".as_ref(), &key[..]].concat();
+	let digest = hash(MessageDigest::sha3_256(), &buf).expect("sha3_256 not available");
+	buf.zeroize();
+	digest[..L_CODE_CHECK].to_vec()
+}
+
+/// the key check value in groups of four hex digits, to be compared by eye
+fn fingerprint(check: &[u8]) -> String {
+	check.chunks(2).map(slice_to_hex).collect::<Vec<_>>().join("-")
+}
+
+/// derive the subkey for `purpose` from the master key with HKDF-SHA256. `info` distinguishes
+/// several keys of the same purpose, e.g. one per file.
+fn derive_key(master: &Key, purpose: Purpose, info: &[u8]) -> Key {
+	let mut subkey = Key::zero();
+	let mut ctx = PkeyCtx::new_id(Id::HKDF).expect("HKDF not available");
+	ctx.derive_init().unwrap();
+	ctx.set_hkdf_md(Md::sha256()).unwrap();
+	ctx.set_hkdf_key(&master[..]).unwrap();
+	ctx.add_hkdf_info(b"symsync subkey").unwrap();
+	ctx.add_hkdf_info(&[purpose as u8]).unwrap();
+	ctx.add_hkdf_info(info).unwrap();
+	ctx.derive(Some(&mut subkey)).expect("HKDF failed");
+	subkey
+}
+
+/// the header of the table that starts at `line` of a TOML file, e.g. `[[keyring]]`
+fn toml_table(line: &str) -> Option<&str> {
+	let line = line.trim();
+	if line.starts_with('[') {
+		Some(line.split('#').next().unwrap().trim_end())
+	} else {
+		None
+	}
+}
+
+/// the key that is set at `line` of a TOML file. None for comments and table headers.
+fn toml_key(line: &str) -> Option<&str> {
+	let line = line.trim_start();
+	if line.starts_with('#') || line.starts_with('[') {
+		return None;
+	}
+	line.split_once('=').map(|(key, _)| key.trim())
+}
+
+/// generate a nonce for aes_256_gcm
+fn gen_nonce() -> Nonce {
+	let mut buf: Nonce = [0; L_NONCE];
+	rand_bytes(&mut buf).unwrap();
+	buf
+}
+
+
+
+fn gen_obfuscation() -> Vec<u8>{
+    let exp = Exp::new(0.005).unwrap();
+    let l = exp.sample(&mut rand::thread_rng()) as usize + 3;
+    //println!("length: {:?}", l);
+    let mut v = vec![0; l];
+    v[l-3] = (l>>16) as u8;
+    v[l-2] = (l>>8) as u8;
+    v[l-1] = l as u8;
+    v
+}
+
+impl Padding {
+	/// number of bytes at the end of the padding that hold its length
+	fn l_len(self) -> usize {
+		match self {
+			Padding::None => 0,
+			Padding::Exponential => 3,
+			_ => L_PADDING_LEN,
+		}
+	}
+
+	/// length of a message of length `l` after padding
+	fn padded_len(self, l: usize) -> usize {
+		let l = l + self.l_len();
+		match self {
+			Padding::None | Padding::Exponential => l,
+			Padding::Padme => {
+				if l < 4 {
+					return l;
+				}
+				let e = usize::BITS - 1 - l.leading_zeros();
+				let s = u32::BITS - e.leading_zeros();
+				let mask = (1 << (e - s)) - 1;
+				(l + mask) & !mask
+			}
+			Padding::Pow2 => l.next_power_of_two(),
+			Padding::Block(b) => l.div_ceil(b as usize) * b as usize,
+		}
+	}
+
+	/// the padding for a message of length `l`
+	fn generate(self, l: usize) -> Vec<u8> {
+		match self {
+			Padding::None => Vec::new(),
+			Padding::Exponential => gen_obfuscation(),
+			_ => {
+				let l_padding = self.padded_len(l) - l;
+				let mut v = vec![0; l_padding];
+				v[l_padding-L_PADDING_LEN..].copy_from_slice(&(l_padding as u64).to_be_bytes());
+				v
+			}
+		}
+	}
+
+	/// length of the padding that ends with `tail`. `tail` has at least `l_len` bytes.
+	fn len_from_tail(self, tail: &[u8]) -> u64 {
+		tail[tail.len()-self.l_len()..].iter().fold(0, |l, b| (l << 8) + u64::from(*b))
+	}
+
+	/// remove the padding from the end of a message
+	fn strip(self, mut message: Vec<u8>) -> Result<Vec<u8>> {
+		let l = message.len();
+		if l < self.l_len() {
+			return Err("message too short to contain obfuscation".into());
+		}
+		let l_padding = self.len_from_tail(&message);
+		if l_padding < self.l_len() as u64 || l_padding > l as u64 {
+			return Err("invalid obfuscation length".into());
+		}
+		message.truncate(l - l_padding as usize);
+		Ok(message)
+	}
+
+	/// the header extension describing the padding. The exponential padding has none, such that
+	/// older versions can read it.
+	fn extension(self) -> Option<(u8, Vec<u8>)> {
+		let value = match self {
+			Padding::None => vec![0],
+			Padding::Exponential => return None,
+			Padding::Padme => vec![2],
+			Padding::Pow2 => vec![3],
+			Padding::Block(b) => [&[4], &b.to_be_bytes()[..]].concat(),
+		};
+		Some((EXT_PADDING, value))
+	}
+
+	fn from_bytes(value: &[u8]) -> Result<Self> {
+		match value {
+			[0] => Ok(Padding::None),
+			[2] => Ok(Padding::Padme),
+			[3] => Ok(Padding::Pow2),
+			[4, b @ ..] if b.len() == 4 && b != [0; 4] => Ok(Padding::Block(u32::from_be_bytes(<[u8; 4]>::try_from(b)?))),
+			_ => Err("unknown padding in header. Please update symsync.".into()),
+		}
+	}
+
+	/// the padding named in `extensions`
+	fn from_extensions(extensions: &[(u8, Vec<u8>)]) -> Result<Self> {
+		match extensions.iter().find(|(tag, _)| *tag == EXT_PADDING) {
+			Some((_, value)) => Padding::from_bytes(value),
+			None => Ok(Padding::Exponential),
+		}
+	}
+}
+
+/// Hashes everything that is read through it, for the content hash of a file.
+struct HashReader<R> {
+	reader: R,
+	hasher: openssl::hash::Hasher,
+}
+
+impl<R: Read> HashReader<R> {
+	fn new(reader: R) -> Result<Self> {
+		Ok(HashReader { reader, hasher: openssl::hash::Hasher::new(MessageDigest::sha3_256())? })
+	}
+
+	/// the hash of everything read, in hex
+	fn finish(mut self) -> Result<String> {
+		Ok(slice_to_hex(&self.hasher.finish()?))
+	}
+}
+
+impl<R: Read> Read for HashReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let l = self.reader.read(buf)?;
+		self.hasher.update(&buf[..l])?;
+		Ok(l)
+	}
+}
+
+/// content hash of the file `path`, as in `Metadata::hash`
+fn hash_file(path: &Path) -> Result<String> {
+	let mut reader = HashReader::new(myopen(path)?)?;
+	io::copy(&mut reader, &mut io::sink())?;
+	reader.finish()
+}
+
+/// appends the padding to a message that is read from `reader`, once its length is known
+struct Padded<R> {
+	reader: R,
+	padding: Padding,
+	l: usize,							// length of the message read so far
+	tail: Option<io::Cursor<Vec<u8>>>,	// the padding, once the message is read
+}
+
+impl<R: Read> Read for Padded<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if let Some(tail) = &mut self.tail {
+			return tail.read(buf);
+		}
+		let k = self.reader.read(buf)?;
+		if k > 0 || buf.is_empty() {
+			self.l += k;
+			return Ok(k);
+		}
+		self.tail = Some(io::Cursor::new(self.padding.generate(self.l)));
+		self.read(buf)
+	}
+}
+
+/// remove the obfuscation appended by `gen_obfuscation`
+fn strip_obfuscation(message: Vec<u8>) -> Result<Vec<u8>> {
+	Padding::Exponential.strip(message)
+}
+
+
+
+/// encrypt a message with aes_256_gcm. A random number of zeros is appended to the message to hide
+/// its length. This number is saved in the last entries of the new message. A padding extension in
+/// `extensions` selects another padding.
+/// `aad` is authenticated but not encrypted. It has to be given again for decryption.
+/// The message is encrypted with the subkey for `purpose` and `info`, which is derived from `key`.
+/// Return the header, followed by the ciphertext and the tag. 
+fn my_encrypt(message: &[u8], key: &Key, aad: &[u8], purpose: Purpose, info: &[u8]) -> Result<Vec<u8>> {
+	my_encrypt_ext(message, key, aad, purpose, info, Vec::new())
+}
+
+/// like `my_encrypt`, with additional extensions in the header
+fn my_encrypt_ext(message: &[u8], key: &Key, aad: &[u8], purpose: Purpose, info: &[u8],
+	mut extensions: Vec<(u8, Vec<u8>)>) -> Result<Vec<u8>> {
+
+	let nonce = gen_nonce();
+	let padding = Padding::from_extensions(&extensions)?;
+	let mut header = Header::new(Algorithm::Aes256Gcm, key, &nonce);
+	extensions.push((EXT_SUBKEY, [&[purpose as u8], info].concat()));
+	header.extensions = extensions;
+	let header = header.to_bytes();
+	let key = &derive_key(key, purpose, info);
+    let new_message = [message, &padding.generate(message.len())].concat();
+	let mut tag = [0; L_TAG];
+	let aad = [&header[..], aad].concat();
+	let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), &key[..], Some(&nonce), &aad, &new_message, &mut tag)?;
+	Ok([&header[..], &ciphertext, &tag].concat())
+}
+
+/// decrypt a message encrypted by `my_encrypt` with the key named in the header. Throw a SigError
+/// if the tag does not match, that is if the ciphertext, the header or `aad` were modified. Throw a
+/// KeyIdError if the key is not in the keyring.
+fn my_decrypt(blob: &[u8], keyring: &Keyring, aad: &[u8]) -> Result<Vec<u8>> {
+	let (header, l) = Header::from_bytes(blob)?;
+	if header.algorithm == Algorithm::Aes256GcmStream {
+		let mut message = Vec::new();
+		decrypt_stream(io::Cursor::new(blob), &mut message, keyring, aad)?;
+		return Ok(message);
+	}
+	let key = header.key(keyring)?;
+	let rest = &blob[l..];
+	if rest.len() < L_TAG {
+		return Err(SigError.into());
+	}
+	let (ciphertext, tag) = rest.split_at(rest.len() - L_TAG);
+	let aad = [&blob[..l], aad].concat();
+	let message = decrypt_aead(Cipher::aes_256_gcm(), &key[..], Some(&header.nonce), &aad, ciphertext, tag)
+		.map_err(|_| SigError)?;
+	header.padding()?.strip(message)
+}
+
+/// nonce of chunk `i` of a blob in chunks: the prefix from the header, the counter and whether it is the last chunk
+fn stream_nonce(prefix: &[u8], i: u32, last: bool) -> Nonce {
+	let mut nonce = [0; L_NONCE];
+	nonce[..L_NONCE_PREFIX].copy_from_slice(prefix);
+	nonce[L_NONCE_PREFIX..L_NONCE-1].copy_from_slice(&i.to_be_bytes());
+	nonce[L_NONCE-1] = last as u8;
+	nonce
+}
+
+/// read until `buf` is full or `reader` is at its end. Return the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+	let mut l = 0;
+	while l < buf.len() {
+		match reader.read(&mut buf[l..]) {
+			Ok(0) => break,
+			Ok(k) => l += k,
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(l)
+}
+
+/// like `my_encrypt_ext`, but reads the message from `reader` and writes the blob to `writer` in chunks
+/// of L_CHUNK bytes (STREAM construction). Every chunk has its own tag and the nonce marks the last chunk,
+/// such that chunks cannot be reordered, dropped or truncated. The padding is appended to the message.
+fn encrypt_stream<R: Read, W: Write>(reader: R, mut writer: W, key: &Key, aad: &[u8], purpose: Purpose, info: &[u8],
+	mut extensions: Vec<(u8, Vec<u8>)>) -> Result<()> {
+
+	let mut prefix = [0; L_NONCE_PREFIX];
+	rand_bytes(&mut prefix)?;
+	let padding = Padding::from_extensions(&extensions)?;
+	let mut header = Header::new(Algorithm::Aes256GcmStream, key, &prefix);
+	extensions.push((EXT_SUBKEY, [&[purpose as u8], info].concat()));
+	extensions.push((EXT_CHUNK, (L_CHUNK as u32).to_be_bytes().to_vec()));
+	header.extensions = extensions;
+	let header = header.to_bytes();
+	writer.write_all(&header)?;
+	let key = derive_key(key, purpose, info);
+	let aad = [&header[..], aad].concat();
+	let mut reader = Padded { reader, padding, l: 0, tail: None };
+	let mut chunk = vec![0; L_CHUNK];
+	let mut next = vec![0; L_CHUNK];
+	let mut l = read_full(&mut reader, &mut chunk)?;
+	let mut i: u32 = 0;
+	loop {
+		// a chunk is the last one if nothing follows, so every chunk but the last is full
+		let l_next = if l == L_CHUNK {read_full(&mut reader, &mut next)?} else {0};
+		let mut tag = [0; L_TAG];
+		let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), &key, Some(&stream_nonce(&prefix, i, l_next == 0)),
+			&aad, &chunk[..l], &mut tag)?;
+		writer.write_all(&ciphertext)?;
+		writer.write_all(&tag)?;
+		if l_next == 0 {
+			break;
+		}
+		std::mem::swap(&mut chunk, &mut next);
+		l = l_next;
+		i = i.checked_add(1).ok_or("file too large")?;
+	}
+	writer.flush()?;
+	Ok(())
+}
+
+/// read and decrypt chunk `i` of length `l` (including the tag) at the current position of `reader`
+fn read_chunk<R: Read>(reader: &mut R, key: &Key, prefix: &[u8], aad: &[u8], i: u32, last: bool, l: usize) -> Result<Vec<u8>> {
+	let mut buf = vec![0; l];
+	reader.read_exact(&mut buf).map_err(|_| SigError)?;
+	let (ciphertext, tag) = buf.split_at(l - L_TAG);
+	let message = decrypt_aead(Cipher::aes_256_gcm(), key, Some(&stream_nonce(prefix, i, last)), aad, ciphertext, tag)
+		.map_err(|_| SigError)?;
+	Ok(message)
+}
+
+/// decrypt a blob written by `encrypt_stream` from `reader` to `writer`. Every chunk is authenticated before
+/// it is written. The last chunk is decrypted first to find the length of the padding.
+/// Throw a SigError if a chunk was modified, dropped or reordered.
+fn decrypt_stream<R: Read + Seek, W: Write>(mut reader: R, mut writer: W, keyring: &Keyring, aad: &[u8]) -> Result<()> {
+	let (header, header_bytes) = Header::read_from(&mut reader)?;
+	if header.algorithm != Algorithm::Aes256GcmStream || header.nonce.len() != L_NONCE_PREFIX {
+		return Err("the blob is not encrypted in chunks".into());
+	}
+	let key = header.key(keyring)?;
+	let l_chunk = header.chunk_size()?.ok_or("no chunk size in header")?;
+	let aad = [&header_bytes[..], aad].concat();
+	let start = header_bytes.len() as u64;
+	let l_sealed = (l_chunk + L_TAG) as u64;
+	let l_body = reader.seek(SeekFrom::End(0))? - start;
+	let n = u32::try_from(l_body.div_ceil(l_sealed)).map_err(|_| SigError)?;
+	if n == 0 || l_body - u64::from(n-1) * l_sealed < L_TAG as u64 {
+		return Err(SigError.into());
+	}
+	let l_last = (l_body - u64::from(n-1) * l_sealed) as usize;
+	let l_message = u64::from(n-1) * l_chunk as u64 + (l_last - L_TAG) as u64;
+	let padding = header.padding()?;
+
+	// the length of the padding is in its last bytes, which may reach into the chunk before
+	reader.seek(SeekFrom::Start(start + u64::from(n-1) * l_sealed))?;
+	let mut tail = read_chunk(&mut reader, &key, &header.nonce, &aad, n-1, true, l_last)?;
+	if tail.len() < padding.l_len() && n > 1 {
+		reader.seek(SeekFrom::Start(start + u64::from(n-2) * l_sealed))?;
+		let before = read_chunk(&mut reader, &key, &header.nonce, &aad, n-2, false, l_sealed as usize)?;
+		tail = [before, tail].concat();
+	}
+	if tail.len() < padding.l_len() {
+		return Err("message too short to contain obfuscation".into());
+	}
+	let oblen = padding.len_from_tail(&tail);
+	if oblen < padding.l_len() as u64 || oblen > l_message {
+		return Err("invalid obfuscation length".into());
+	}
+
+	let mut remaining = l_message - oblen;
+	reader.seek(SeekFrom::Start(start))?;
+	for i in 0..n {
+		let last = i == n-1;
+		let l = if last {l_last} else {l_sealed as usize};
+		let message = read_chunk(&mut reader, &key, &header.nonce, &aad, i, last, l)?;
+		let k = remaining.min(message.len() as u64) as usize;
+		writer.write_all(&message[..k])?;
+		remaining -= k as u64;
+	}
+	writer.flush()?;
+	Ok(())
+}
+
+/// encrypt the content of a file for `gpath`. The metadata is embedded encrypted in the header, such that
+/// `symsync recover` can rebuild the image. With a passphrase, the parameters of the kdf are embedded as well.
+fn encrypt_blob<R: Read, W: Write>(reader: R, writer: W, meta: &Metadata, key: &Key, kdf: Option<&KdfParams>,
+	format: &BlobFormat) -> Result<()> {
+	let embedded = EmbeddedMetadata {
+		name: meta.name.clone(),
+		modified: meta.modified,
+		actually_modified: meta.actually_modified,
+		pieces: meta.pieces.is_some(),
+		version: meta.version.clone(),
+	};
+	let embedded = my_encrypt(serde_json::to_string(&embedded)?.as_bytes(), key, b"metadata", Purpose::Metadata, b"")?;
+	let mut extensions = vec![(EXT_METADATA, embedded)];
+	if let Some(kdf) = kdf {
+		extensions.push((EXT_KDF, kdf.to_bytes()));
+	}
+	extensions.extend(format.padding.extension());
+	let info = if format.per_file_keys {meta.namehash.as_bytes()} else {b""};
+	encrypt_stream(reader, writer, key, &meta.aad(), Purpose::Blob, info, extensions)
+}
+
+/// Splits a file into pieces with content-defined boundaries: a piece ends where the rolling hash of
+/// the last 64 bytes has PIECE_BITS leading zeros. An insertion or deletion thus only changes the
+/// pieces around it. The hash is keyed, such that the boundaries do not leak the content.
+struct Chunker<R: Read> {
+	reader: R,
+	gear: Vec<u64>,		// random value for every byte
+	buf: Vec<u8>,		// data read but not returned yet
+}
+
+impl<R: Read> Chunker<R> {
+	fn new(reader: R, key: &Key) -> Result<Self> {
+		let stream = encrypt(Cipher::aes_256_ctr(), &derive_key(key, Purpose::Piece, b"gear"), Some(&[0; L_IV]), &[0; 256 * 8])?;
+		let gear = stream.chunks(8).map(|b| u64::from_be_bytes(<[u8; 8]>::try_from(b).unwrap())).collect();
+		Ok(Chunker { reader, gear, buf: Vec::new() })
+	}
+
+	/// return the next piece, None at the end
+	fn next_piece(&mut self) -> io::Result<Option<Vec<u8>>> {
+		let l = self.buf.len();
+		self.buf.resize(L_PIECE_MAX, 0);
+		let k = read_full(&mut self.reader, &mut self.buf[l..])?;
+		self.buf.truncate(l + k);
+		if self.buf.is_empty() {
+			return Ok(None);
+		}
+		let mut end = self.buf.len();
+		let mut hash: u64 = 0;
+		for (i, b) in self.buf.iter().enumerate().skip(L_PIECE_MIN) {
+			hash = (hash << 1).wrapping_add(self.gear[*b as usize]);
+			if hash >> (64 - PIECE_BITS) == 0 {
+				end = i + 1;
+				break;
+			}
+		}
+		let rest = self.buf.split_off(end);
+		Ok(Some(std::mem::replace(&mut self.buf, rest)))
+	}
+}
+
+/// name of a piece: a keyed hash of its content, such that an unchanged piece keeps its name and
+/// identical pieces of different files are stored only once
+fn piece_name(piece: &[u8], key: &Key) -> String {
+	let mut mac = Hmac::<Sha3_256>::new_varkey(&derive_key(key, Purpose::Piece, b"name")).unwrap();
+	mac.input(piece);
+	slice_to_hex(&mac.result().code()[..L_PIECE_NAME])
+}
+
+/// split the content of a file into pieces and write every piece that is not in `gpath` yet as its own blob.
+/// The list of pieces is written to the blob of the file, `meta.namehash`, and recorded in `meta` together
+/// with the signature.
+fn write_pieces<R: Read>(reader: R, store: &BlobStore, meta: &mut Metadata, key: &Key, kdf: Option<&KdfParams>,
+	format: &BlobFormat) -> Result<()> {
+
+	let gpath = store.gpath;
+	let compression = if is_compressed_format(&meta.name) {Compression::None} else {format.compression};
+	let mut reader = HashReader::new(reader)?;
+	let mut chunker = Chunker::new(&mut reader, key)?;
+	let mut pieces = Vec::new();
+	while let Some(piece) = chunker.next_piece()? {
+		let name = piece_name(&piece, key);
+		let path = gpath.join(&name);
+		if !store.has_piece(&name) {
+			let info = if format.per_file_keys {name.as_bytes()} else {b""};
+			let mut blob = Vec::new();
+			let mut extensions: Vec<_> = format.padding.extension().into_iter().collect();
+			match compress(&piece, compression)? {
+				Some(compressed) => {
+					extensions.push((EXT_COMPRESSION, vec![compression.id()]));
+					encrypt_stream(&compressed[..], &mut blob, key, name.as_bytes(), Purpose::Piece, info, extensions)?;
+				}
+				None => encrypt_stream(&piece[..], &mut blob, key, name.as_bytes(), Purpose::Piece, info, extensions)?,
+			}
+			// never leave a half written piece under its name
+			let tmp = gpath.join(format!("{}.tmp", &name));
+			writefile(&tmp, &blob)?;
+			fs::rename(&tmp, &path)?;
+		}
+		pieces.push(name);
+	}
+	drop(chunker);
+	meta.hash = Some(reader.finish()?);
+	meta.pieces = Some(pieces);
+	let tmp = gpath.join(format!("{}.tmp", &meta.namehash));
+	encrypt_blob(&serde_json::to_vec(&meta.pieces)?[..], createfile(&tmp)?, meta, key, kdf, format)?;
+	meta.signature = slice_to_hex(&calc_mac_reader(myopen(&tmp)?, key)?);
+	fs::rename(&tmp, gpath.join(&meta.namehash))?;
+	Ok(())
+}
+
+/// true if the extension of `name` belongs to a format that is compressed already
+fn is_compressed_format(name: &str) -> bool {
+	match Path::new(name).extension().and_then(|ext| ext.to_str()) {
+		Some(ext) => COMPRESSED_FORMATS.contains(&ext.to_lowercase().as_str()),
+		None => false,
+	}
+}
+
+/// compress a piece. None if it is not compressed, because it would not get much smaller.
+fn compress(piece: &[u8], compression: Compression) -> Result<Option<Vec<u8>>> {
+	match compression {
+		Compression::None => Ok(None),
+		Compression::Deflate => {
+			let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+			encoder.write_all(piece)?;
+			let compressed = encoder.finish()?;
+			Ok(if compressed.len() < piece.len() - piece.len() / 16 {Some(compressed)} else {None})
+		}
+	}
+}
+
+/// undo `compress`. A piece is never larger than L_PIECE_MAX.
+fn decompress(data: Vec<u8>, compression: Compression) -> Result<Vec<u8>> {
+	match compression {
+		Compression::None => Ok(data),
+		Compression::Deflate => {
+			let mut piece = Vec::new();
+			DeflateDecoder::new(&data[..]).take(L_PIECE_MAX as u64 + 1).read_to_end(&mut piece)?;
+			if piece.len() > L_PIECE_MAX {
+				return Err("decompressed piece is too large".into());
+			}
+			Ok(piece)
 		}
 	}
-    files.sort();
-    files
 }
 
+/// decrypt a piece and check that its content matches its name
+fn open_piece(blob: &[u8], name: &str, keyring: &Keyring) -> Result<Vec<u8>> {
+	let (header, _) = Header::from_bytes(blob)?;
+	let piece = decompress(my_decrypt(blob, keyring, name.as_bytes())?, header.compression()?)?;
+	let key = keyring.for_header(&header)?;
+	let matches = |expected: String| expected.len() == name.len() && memcmp::eq(expected.as_bytes(), name.as_bytes());
+	if !matches(piece_name(&piece, key)) {
+		return Err(SigError.into());
+	}
+	Ok(piece)
+}
 
-/// read a file and return its content
-fn readfile(fname: &Path)-> io::Result<Vec<u8>> {
-	let mut f = myopen(fname)?;
-	let mut buffer = Vec::new();
-	match f.read_to_end(&mut buffer){
-        Ok(_) => {Ok(buffer)}
-        Err(e) => {
-            println!("read_to_end in readfile failed");
-            Err(e)
-        }
-    }
+/// bytes that a blob takes in a pack: the length of the name, the name, the length of the blob and the blob
+fn packed_len(name: &str, l_blob: usize) -> usize {
+	1 + name.len() + 4 + l_blob
 }
 
-/// write content to file
-fn writefile(fname: &Path, content: &[u8]) -> io::Result<()> {
-	match File::create(fname) {
-		Ok(mut file) => {
-			match file.write_all(&content){
-                Ok(_) => {}
-                Err(e) => {println!("file.write_all failed in writefile. Error message: {:?}", e); return Err(e)}
-			}
-        }
-		Err(_e) => {
-			let folder = fname.parent().expect("error in writefile trying to get parent of fname");
-			match fs::create_dir_all(&folder){
-                Ok(_) => {}
-                Err(e) => {println!("create_dir_all failed for folder {:?} in writefile. Error message: {:?}", &folder, e); return Err(e)}
-            }
-			match writefile(&fname, &content){
-                Ok(_) => {}
-                Err(e) => {println!("writefile failed in writefile. Error message: {:?}", e); return Err(e)}
-            }
+/// blobs with their names, as in a pack
+type Blobs = Vec<(String, Vec<u8>)>;
+
+/// encrypt `blobs` into a pack named `name`. The content is filled up with zeros to `capacity`, such that
+/// every pack has the same size. A pack without blobs is a decoy.
+fn seal_pack(blobs: &[(String, Vec<u8>)], name: &str, capacity: usize, key: &Key, kdf: Option<&KdfParams>,
+	format: &BlobFormat) -> Result<Vec<u8>> {
+
+	let mut message = Vec::with_capacity(capacity);
+	for (blobname, blob) in blobs {
+		message.push(u8::try_from(blobname.len())?);
+		message.extend(blobname.as_bytes());
+		message.extend(&u32::try_from(blob.len())?.to_be_bytes());
+		message.extend(blob);
+	}
+	if message.len() > capacity {
+		return Err("blobs do not fit into the pack".into());
+	}
+	message.resize(capacity, 0);
+	let mut extensions: Vec<_> = Padding::None.extension().into_iter().collect();
+	if let Some(kdf) = kdf {
+		extensions.push((EXT_KDF, kdf.to_bytes()));
+	}
+	let info = if format.per_file_keys {name.as_bytes()} else {b""};
+	my_encrypt_ext(&message, key, name.as_bytes(), Purpose::Pack, info, extensions)
+}
+
+/// decrypt a pack and return the blobs in it
+fn open_pack(blob: &[u8], name: &str, keyring: &Keyring) -> Result<Blobs> {
+	let message = my_decrypt(blob, keyring, name.as_bytes())?;
+	let mut blobs = Vec::new();
+	let mut rest = &message[..];
+	// the blobs are followed by zeros
+	while let Some((&l_name, r)) = rest.split_first() {
+		if l_name == 0 {
+			break;
 		}
-    }
-	Ok(())
+		if r.len() < l_name as usize + 4 {
+			return Err("invalid pack".into());
+		}
+		let (blobname, r) = r.split_at(l_name as usize);
+		let (l_blob, r) = r.split_at(4);
+		let l_blob = u32::from_be_bytes(<[u8; 4]>::try_from(l_blob)?) as usize;
+		if r.len() < l_blob {
+			return Err("invalid pack".into());
+		}
+		let (content, r) = r.split_at(l_blob);
+		blobs.push((String::from_utf8(blobname.to_vec())?, content.to_vec()));
+		rest = r;
+	}
+	Ok(blobs)
+}
+
+/// Fills packs with blobs and writes them to `gpath`.
+struct Packer<'a> {
+	gpath: &'a Path,
+	key: &'a Key,
+	kdf: Option<&'a KdfParams>,
+	format: &'a BlobFormat,
+	capacity: usize,				// bytes of blobs that fit into a pack
+	blobs: Blobs,					// blobs of the pack that is filled
+	used: usize,					// bytes used by `blobs`
+	packs: Vec<Pack>,				// packs that were written
 }
 
+impl<'a> Packer<'a> {
+	fn new(gpath: &'a Path, pack_size: usize, key: &'a Key, kdf: Option<&'a KdfParams>, format: &'a BlobFormat) -> Result<Self> {
+		// header and tag have the same size for every pack
+		let overhead = seal_pack(&[], &"0".repeat(2 * L_PACK_NAME), 0, key, kdf, format)?.len();
+		let capacity = pack_size.checked_sub(overhead).ok_or("pack_size is too small")?;
+		Ok(Packer { gpath, key, kdf, format, capacity, blobs: Vec::new(), used: 0, packs: Vec::new() })
+	}
 
+	/// add a blob to the pack that is filled. A full pack is written first.
+	fn add(&mut self, name: String, blob: Vec<u8>) -> Result<()> {
+		let l = packed_len(&name, blob.len());
+		if self.used + l > self.capacity {
+			self.flush()?;
+		}
+		self.used += l;
+		self.blobs.push((name, blob));
+		Ok(())
+	}
 
+	/// write the pack that is filled
+	fn flush(&mut self) -> Result<()> {
+		if self.blobs.is_empty() {
+			return Ok(());
+		}
+		let name = self.write(&self.blobs)?;
+		let blobs = self.blobs.drain(..).map(|(blobname, blob)| (blobname, blob.len() as u32)).collect();
+		self.packs.push(Pack { name, blobs });
+		self.used = 0;
+		Ok(())
+	}
 
-/// generate an IV (initial vector for aes)
-fn gen_iv() -> Iv {
-	let mut buf: Iv = [0; L_IV];
-	rand_bytes(&mut buf).unwrap();
-	buf
+	/// write a pack with `blobs` under a random name and return the name
+	fn write(&self, blobs: &[(String, Vec<u8>)]) -> Result<String> {
+		let mut name = [0; L_PACK_NAME];
+		rand_bytes(&mut name)?;
+		let name = slice_to_hex(&name);
+		let pack = seal_pack(blobs, &name, self.capacity, self.key, self.kdf, self.format)?;
+		// never leave a half written pack under its name
+		let tmp = self.gpath.join(format!("{}.tmp", &name));
+		writefile(&tmp, &pack)?;
+		fs::rename(&tmp, self.gpath.join(&name))?;
+		Ok(name)
+	}
 }
 
-/// generate two keys for siphash 
-fn gen_sipkey() -> (u64, u64) {
-	let mut buf = [0; 8];
-	rand_bytes(&mut buf).unwrap();
-    let k1 = u64::from_be_bytes(buf);
-	rand_bytes(&mut buf).unwrap();
-    let k2 = u64::from_be_bytes(buf);
-	(k1, k2)
+/// Reads blobs from `gpath`, from the pack they are in or else from their own file.
+struct BlobStore<'a> {
+	gpath: &'a Path,
+	keyring: &'a Keyring,
+	index: HashMap<&'a str, &'a str>,				// name of the pack of every packed blob
+	opened: Option<(String, Blobs)>,				// the pack that was read last
 }
 
+impl<'a> BlobStore<'a> {
+	fn new(gpath: &'a Path, packs: &'a [Pack], keyring: &'a Keyring) -> Self {
+		let index = packs.iter()
+			.flat_map(|pack| pack.blobs.iter().map(move |(name, _)| (name.as_str(), pack.name.as_str())))
+			.collect();
+		BlobStore { gpath, keyring, index, opened: None }
+	}
 
-fn gen_obfuscation() -> Vec<u8>{
-    let exp = Exp::new(0.005).unwrap();
-    let l = exp.sample(&mut rand::thread_rng()) as usize + 3;
-    //println!("length: {:?}", l);
-    let mut v = Vec::with_capacity(l);
-    v.resize(l, 0);
-    v[l-3] = (l>>16) as u8;
-    v[l-2] = (l>>8) as u8;
-    v[l-1] = l as u8;
-    return v
+	/// true if the blob is in a pack or has a file
+	fn contains(&self, name: &str) -> bool {
+		self.index.contains_key(name) || self.gpath.join(name).exists()
+	}
+
+	/// true if the piece is in a pack or its file can be opened. A damaged file is not taken for the piece,
+	/// such that it is written again.
+	fn has_piece(&self, name: &str) -> bool {
+		let path = self.gpath.join(name);
+		self.index.contains_key(name)
+			|| path.exists() && readfile(&path).is_ok_and(|blob| open_piece(&blob, name, self.keyring).is_ok())
+	}
+
+	fn read(&mut self, name: &str) -> Result<Vec<u8>> {
+		let pack = match self.index.get(name) {
+			Some(pack) => *pack,
+			None => return Ok(readfile(&self.gpath.join(name))?),
+		};
+		if self.opened.as_ref().map(|(opened, _)| opened.as_str()) != Some(pack) {
+			let blobs = open_pack(&readfile(&self.gpath.join(pack))?, pack, self.keyring)?;
+			self.opened = Some((pack.to_string(), blobs));
+		}
+		let (_, blobs) = self.opened.as_ref().unwrap();
+		match blobs.iter().find(|(blobname, _)| blobname == name) {
+			Some((_, blob)) => Ok(blob.clone()),
+			None => Err(format!("blob {} is missing in pack {}", name, pack).into()),
+		}
+	}
+
+	/// header of a blob. Only the header is read if the blob has its own file.
+	fn header(&mut self, name: &str) -> Result<Header> {
+		if self.index.contains_key(name) {
+			return Ok(Header::from_bytes(&self.read(name)?)?.0);
+		}
+		Ok(Header::read_from(&mut myopen(&self.gpath.join(name))?)?.0)
+	}
 }
 
+/// rebuild the entry of a blob named `blobname` from the metadata embedded in its header. The blob is
+/// decrypted to check that it matches.
+fn recover_blob(blob: &[u8], blobname: &str, keyring: &Keyring) -> Result<Metadata> {
+	let (header, _) = Header::from_bytes(blob)?;
+	let embedded = header.metadata(keyring)?.ok_or("the blob was written by an older version without metadata")?;
+	let metadata = Metadata {
+		name: embedded.name,
+		namehash: blobname.to_string(),
+		modified: embedded.modified,
+		actually_modified: embedded.actually_modified,
+		signature: slice_to_hex(&calc_mac(blob, keyring.for_header(&header)?)),
+		iv: None,
+		algorithm: header.algorithm,
+		signature_type: SignatureType::HmacSha3,
+		pieces: None,
+		hash: None,
+		stat: None,
+		version: embedded.version,
+		written_by: None,
+	};
+	let message = open_blob(blob, &metadata, keyring, None)?;
+	if embedded.pieces {
+		return Ok(Metadata { pieces: Some(serde_json::from_slice(&message)?), ..metadata });
+	}
+	Ok(metadata)
+}
 
+/// decrypt a blob from `gpath` and check that it belongs to `metadata`.
+/// Throw a SigError if it does not. The HMAC is checked before anything is decrypted.
+fn open_blob(blob: &[u8], metadata: &Metadata, keyring: &Keyring, siphashkey: Option<(u64, u64)>) -> Result<Vec<u8>> {
+	if metadata.signature_type == SignatureType::HmacSha3 {
+		check_mac(blob, &metadata.signature, keyring)?;
+		return my_decrypt(blob, keyring, &metadata.aad());
+	}
+	match metadata.algorithm {
+		Algorithm::Aes256Cbc => {
+			let iv = metadata.iv.ok_or("CBC encrypted entry without IV")?;
+			let siphashkey = siphashkey.ok_or(SigError)?;
+			keyring.candidates().into_iter()
+				.filter_map(|key| my_decrypt_cbc(blob, key, &iv).ok())
+				.find(|message| check_signature(&metadata.signature, message, &siphashkey).is_ok())
+				.ok_or_else(|| SigError.into())
+		}
+		Algorithm::Aes256Gcm | Algorithm::Aes256GcmStream => {
+			if blob.len() < L_TAG || slice_to_hex(&blob[blob.len()-L_TAG..]) != metadata.signature {
+				return Err(SigError.into());
+			}
+			my_decrypt(blob, keyring, &metadata.aad())
+		}
+	}
+}
 
+/// like `open_blob`, but reads the blob of `metadata` from `store` and writes the content to `out`. Blobs in
+/// chunks are decrypted chunk by chunk into .sync/ and then moved to `out`, such that the memory use does not
+/// depend on the size of the file and `out` is never left half written.
+fn open_blob_file(store: &mut BlobStore, metadata: &Metadata, siphashkey: Option<(u64, u64)>, out: &Path) -> Result<()> {
+	let keyring = store.keyring;
+	if metadata.algorithm != Algorithm::Aes256GcmStream || metadata.signature_type != SignatureType::HmacSha3 {
+		let message = open_blob(&store.read(&metadata.namehash)?, metadata, keyring, siphashkey)?;
+		writefile(out, &message)?;
+		return Ok(());
+	}
+	let download: PathBuf = [FOLDER_SYNC, DOWNLOAD].iter().collect();
+	let mut decrypt_to_download = || -> Result<()> {
+		match &metadata.pieces {
+			Some(pieces) => {
+				check_mac(&store.read(&metadata.namehash)?[..], &metadata.signature, keyring)?;
+				let mut f = File::create(&download)?;
+				for name in pieces {
+					f.write_all(&open_piece(&store.read(name)?, name, keyring)?)?;
+				}
+			}
+			None => {
+				// blobs that hold a whole file are never packed
+				let path = store.gpath.join(&metadata.namehash);
+				check_mac(myopen(&path)?, &metadata.signature, keyring)?;
+				decrypt_stream(myopen(&path)?, File::create(&download)?, keyring, &metadata.aad())?;
+			}
+		}
+		Ok(())
+	};
+	// do not leave partial plaintext behind
+	if let Err(e) = decrypt_to_download() {
+		let _ = fs::remove_file(&download);
+		return Err(e);
+	}
+	if let Some(folder) = out.parent() {
+		fs::create_dir_all(folder)?;
+	}
+	fs::rename(&download, out)?;
+	Ok(())
+}
 
-/// encrypt a message with aes_256_cbc. A random number of zeros is appended to the message to hide
-/// its length. This number is saved in the last entries of the new message.
-/// Return the encrypted message and the initial vector (IV). 
-/// The IV is needed for decryption of the first 16 bytes and can be public
-fn my_encrypt(message: &[u8], key: &Key) -> Result<(Vec<u8>, Iv)> {
-	let iv = gen_iv();
-    let ob = gen_obfuscation();
-    let new_message = [message, &ob].concat();
-    //println!("length of new_message: {:?}", new_message.len());
+/// decrypt a message with aes_256_cbc. Only needed for blobs written by older versions.
+fn my_decrypt_cbc(ciphertext: &[u8], key: &Key, iv: &Iv) -> Result<Vec<u8>> {
 	let cipher = Cipher::aes_256_cbc();
-	let ciphertext = encrypt(cipher, &key[..], Some(&iv), &new_message)?;
-    //println!("length of ciphertext: {:?}", ciphertext.len());
-	Ok((ciphertext, iv))
+	let message = decrypt(cipher, &key[..], Some(&iv[..]), ciphertext)?;
+	strip_obfuscation(message)
 }
 
-/// decrypt a message with aes_256_cbc. 
-fn my_decrypt(ciphertext: &[u8], key: &Key, iv: &Iv) -> Result<Vec<u8>> {
-	let cipher = Cipher::aes_256_cbc();
-	let mut message = decrypt(cipher, &key[..], Some(&iv[..]), &ciphertext)?;
-    let l = message.len();
-    let o1: usize = message[l-3].into();
-    let o2: usize = message[l-2].into();
-    let o3: usize = message[l-1].into();
-    let oblen = (o1<<16) + (o2<<8) + o3;
-    message.truncate(l-oblen);
-	Ok(message)
+
+/// synthetic IV of an encrypted name: the HMAC of the name, such that equal names give equal IVs
+fn name_siv(name: &[u8], key: &Key) -> [u8; L_SIV] {
+	let mut mac = Hmac::<Sha3_256>::new_varkey(&derive_key(key, Purpose::Names, b"siv mac")).unwrap();
+	mac.input(name);
+	let mut siv = [0; L_SIV];
+	siv.copy_from_slice(&mac.result().code()[..L_SIV]);
+	siv
+}
+
+/// encrypt a path deterministically such that it can be used as the name of its blob. The synthetic
+/// IV is the initial counter of AES-CTR and is stored in front of the ciphertext, everything base32 encoded.
+/// Paths that would give a name longer than `L_NAME_MAX` (about 140 bytes) are named by the synthetic IV
+/// only. Such a name cannot be decrypted, the path is still embedded in the blob.
+fn encrypt_name(name: &str, key: &Key) -> Result<String> {
+	let siv = name_siv(name.as_bytes(), key);
+	let ciphertext = encrypt(Cipher::aes_256_ctr(), &derive_key(key, Purpose::Names, b"siv enc"), Some(&siv), name.as_bytes())?;
+	let encoded = base32::encode(Alphabet::RFC4648 { padding: false }, &[&siv[..], &ciphertext].concat()).to_lowercase();
+	if encoded.len() > L_NAME_MAX {
+		return Ok(base32::encode(Alphabet::RFC4648 { padding: false }, &siv).to_lowercase());
+	}
+	Ok(encoded)
+}
+
+/// decrypt a name from `encrypt_name`. Throw a SigError if it was not encrypted with `key`. Blobs are
+/// checked by encrypting their path again, this documents that short names can be reversed.
+#[cfg(test)]
+fn decrypt_name(encoded: &str, key: &Key) -> Result<String> {
+	let buf = base32::decode(Alphabet::RFC4648 { padding: false }, encoded).ok_or(SigError)?;
+	if buf.len() < L_SIV {
+		return Err(SigError.into());
+	}
+	let (siv, ciphertext) = buf.split_at(L_SIV);
+	let name = decrypt(Cipher::aes_256_ctr(), &derive_key(key, Purpose::Names, b"siv enc"), Some(siv), ciphertext)?;
+	if !memcmp::eq(&name_siv(&name, key), siv) {
+		return Err(SigError.into());
+	}
+	Ok(String::from_utf8(name)?)
+}
+
+/// calculate the HMAC-SHA3-256 of a blob with the MAC subkey of `key`
+fn calc_mac(blob: &[u8], key: &Key) -> [u8; L_MAC] {
+	// reading from a slice cannot fail
+	calc_mac_reader(blob, key).unwrap()
 }
 
+/// like `calc_mac`, reading the blob from `reader` piece by piece
+fn calc_mac_reader<R: Read>(mut reader: R, key: &Key) -> io::Result<[u8; L_MAC]> {
+	let mut mac = Hmac::<Sha3_256>::new_varkey(&derive_key(key, Purpose::Mac, b"")).unwrap();
+	mac_input(&mut mac, &mut reader)?;
+	let mut result = [0; L_MAC];
+	result.copy_from_slice(&mac.result().code());
+	Ok(result)
+}
+
+/// feed everything from `reader` into `mac`
+fn mac_input<R: Read>(mac: &mut Hmac<Sha3_256>, reader: &mut R) -> io::Result<()> {
+	let mut buf = vec![0; L_CHUNK];
+	loop {
+		let l = read_full(reader, &mut buf)?;
+		mac.input(&buf[..l]);
+		if l < buf.len() {
+			return Ok(());
+		}
+	}
+}
 
-// fn calc_signature_sha3(message: &Vec<u8>, key: &Key) -> [u8; 32] {
-// 	let mut hasher = Sha3_256::new();
-// 	hasher.input(&message);
-// 	hasher.input(&key);
-// 	let result = hasher.result().into();
-// 	result
-// }
+/// check the HMAC of a blob in constant time. The key is the one named in the header of the blob.
+/// Throw a SigError if it does not match.
+fn check_mac<R: Read>(mut reader: R, signature: &str, keyring: &Keyring) -> Result<()> {
+	let (header, header_bytes) = Header::read_from(&mut reader).map_err(|_| SigError)?;
+	let key = keyring.for_header(&header)?;
+	let signature = hex_to_vec(signature).map_err(|_| SigError)?;
+	let mut mac = Hmac::<Sha3_256>::new_varkey(&derive_key(key, Purpose::Mac, b"")).unwrap();
+	mac.input(&header_bytes);
+	mac_input(&mut mac, &mut reader)?;
+	mac.verify(&signature).map_err(|_| SigError.into())
+}
 
-fn calc_signature_sip<T: Hash>(message: &T, siphashkey: &(u64, u64)) -> u64 {
+fn calc_signature_sip<T: Hash + ?Sized>(message: &T, siphashkey: &(u64, u64)) -> u64 {
 	let mut s = SipHasher::new_with_keys(siphashkey.0, siphashkey.1);
-	&message.hash(&mut s);
+	message.hash(&mut s);
 	s.finish()
 }
 
@@ -843,19 +3894,19 @@ fn calc_signature_sip<T: Hash>(message: &T, siphashkey: &(u64, u64)) -> u64 {
 /// calculate the signature of message and compare with given signature
 /// Throw a SigError if they do not match.
 fn check_signature(signature: &str, message: &Vec<u8>, siphashkey: &(u64, u64)) -> Result<()> {
-	let s = format!("{:x}", calc_signature_sip(&message, siphashkey));
-	if signature == s {Ok(())}
+	let s = format!("{:x}", calc_signature_sip(message, siphashkey));
+	if signature.len() == s.len() && memcmp::eq(signature.as_bytes(), s.as_bytes()) {Ok(())}
 	else {Err(SigError.into())}
 }
 
 
 pub fn myopen(fname: &Path) -> io::Result<File> {
-	let r = File::open(&fname);
+	let r = File::open(fname);
     match r {
-        Ok(f) => {return Ok(f)}
+        Ok(f) => {Ok(f)}
         Err(e) => {
             println!("error opening file {:?}", &fname);
-            return Err(e);
+            Err(e)
         }
     }
 }
@@ -863,31 +3914,81 @@ pub fn myopen(fname: &Path) -> io::Result<File> {
 
 
 
-// convert array of numbers to hexadecimal string. 
-// fn slice_to_hex<T: fmt::LowerHex>(a: &[T]) -> String {
-// 	let mut s = String::new();
-// 	for entry in a.iter() {
-// 		s = s + &format!("{:x}", entry);
-// 	}
-// 	s
-// }
+/// convert array of bytes to hexadecimal string. 
+fn slice_to_hex(a: &[u8]) -> String {
+	let mut s = String::new();
+	for entry in a.iter() {
+		s = s + &format!("{:02x}", entry);
+	}
+	s
+}
+
+/// append the bytes to `s` in hexadecimal, without temporary strings
+fn push_hex(s: &mut String, a: &[u8]) {
+	const DIGITS: &[u8; 16] = b"0123456789abcdef";
+	for entry in a {
+		s.push(DIGITS[(entry >> 4) as usize] as char);
+		s.push(DIGITS[(entry & 0xf) as usize] as char);
+	}
+}
+
+/// a key in hexadecimal, as in `key_hex`
+fn key_to_hex(key: &Key) -> SecretString {
+	let mut s = SecretString(String::with_capacity(2*L_KEY + 1));
+	push_hex(&mut s.0, key);
+	s
+}
 
-// convert hexadecimal string to vector of u8 integers. 
-// fn hex_to_u8vec(s: &String) -> Result<Vec<u8>> {
-// 	let l = s.len()/2;
-// 	let mut result = Vec::new();
-// 	for i in 0..l {
-// 		let b = &s[2*i..2*i+2];
-// 		result[i] = u8::from_str_radix(b, 16)?;
-// 	}
-// 	Ok(result)
-// }
+/// convert hexadecimal string to vector of bytes. 
+fn hex_to_vec(s: &str) -> Result<Vec<u8>> {
+	if !s.len().is_multiple_of(2) {
+		return Err("hexadecimal string of odd length".into());
+	}
+	let mut result = Vec::with_capacity(s.len()/2);
+	for i in 0..s.len()/2 {
+		let b = s.get(2*i..2*i+2).ok_or("invalid hexadecimal string")?;
+		result.push(u8::from_str_radix(b, 16)?);
+	}
+	Ok(result)
+}
 
 
 
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use std::ffi::OsString;
+
+    const FORMAT: BlobFormat = BlobFormat { per_file_keys: true, compression: Compression::None, padding: Padding::Exponential,
+        pack_size: None, decoys: 0 };
+
+    /// an empty folder for one test. The name contains the process id, such that test runs do not collide.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("symsync_test_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// metadata of a file named `name` that is not on disk
+    fn test_metadata(name: &str, namehash: &str) -> Metadata {
+        Metadata {
+            name: name.to_string(),
+            namehash: namehash.to_string(),
+            modified: 0,
+            actually_modified: 0,
+            signature: String::new(),
+            iv: None,
+            algorithm: Algorithm::Aes256GcmStream,
+            signature_type: SignatureType::HmacSha3,
+            pieces: None,
+            hash: None,
+            stat: None,
+            version: BTreeMap::new(),
+            written_by: None,
+        }
+    }
+
 	#[test]
 	//fn test_keygen() -> Result<()> {
 	//	let priv_path = Path::new(".");
@@ -912,6 +4013,675 @@ mod tests {
         //assert_eq!(p_copy.push(OsStr::new("x")), OsStr::new("test.txtx"));
     }
     
+    #[test]
+    fn aead_roundtrip_and_tamper() {
+        let key = Key::from([7; L_KEY]);
+        let message = b"some content".to_vec();
+        let mut blob = my_encrypt(&message, &key, b"aad", Purpose::Blob, b"").unwrap();
+        assert_eq!(my_decrypt(&blob, &Keyring::single(key.clone()), b"aad").unwrap(), message);
+        assert!(my_decrypt(&blob, &Keyring::single(key.clone()), b"other aad").unwrap_err().is::<SigError>());
+        let l = blob.len();
+        blob[l-L_TAG-1] ^= 1;
+        assert!(my_decrypt(&blob, &Keyring::single(key.clone()), b"aad").unwrap_err().is::<SigError>());
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let key = Key::from([7; L_KEY]);
+        let header = Header::new(Algorithm::Aes256Gcm, &key, &[1; L_NONCE]);
+        let mut bytes = header.to_bytes();
+        bytes.extend(b"ciphertext");
+        let (parsed, l) = Header::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(&bytes[l..], b"ciphertext");
+        assert!(Header::from_bytes(&bytes[..l-1]).is_err());
+    }
+
+    #[test]
+    fn header_extensions() {
+        let key = Key::from([7; L_KEY]);
+        let kdf = KdfParams::new();
+        let mut header = Header::new(Algorithm::Aes256Gcm, &key, &[1; L_NONCE]);
+        header.extensions.push((EXT_KDF, kdf.to_bytes()));
+        let (parsed, _) = Header::from_bytes(&header.to_bytes()).unwrap();
+        assert_eq!(parsed.kdf().unwrap(), Some(kdf));
+        header.extensions.push((200, vec![1, 2, 3]));
+        assert!(Header::from_bytes(&header.to_bytes()).is_err());
+        // parameters that would need too much memory are refused
+        let greedy = KdfParams { log_n: 21, salt: [5; L_SALT], r: 8, p: 1 };
+        assert!(KdfParams::from_bytes(&greedy.to_bytes()).is_err());
+        let greedy = KdfParams { log_n: 17, r: 64, ..greedy };
+        assert!(KdfParams::from_bytes(&greedy.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn passphrase_derivation() {
+        let kdf = KdfParams { log_n: 10, r: 8, p: 1, salt: [5; L_SALT] };
+        let key = kdf.derive("correct horse").unwrap();
+        assert_eq!(key, kdf.derive("correct horse").unwrap());
+        assert_ne!(key, kdf.derive("battery staple").unwrap());
+        let other_salt = KdfParams { salt: [6; L_SALT], ..kdf };
+        assert_ne!(key, other_salt.derive("correct horse").unwrap());
+    }
+
+    #[test]
+    fn write_key_moves_key_hex_into_the_keyring() {
+        let dir = test_dir("config");
+        let fname = dir.join("config.toml");
+        let old = Key::from([7; L_KEY]);
+        fs::write(&fname, format!("# key_hex in a comment\nkey_hex = \"{}\"\ngpath = \"../remote/\"\n\
+            command_push = \"true\"\ncommand_pull = \"true\"\n", slice_to_hex(&old))).unwrap();
+        let key = Key::from([0xab; L_KEY]);
+        Config::write_key(&fname, &key).unwrap();
+        let string = fs::read_to_string(&fname).unwrap();
+        assert_eq!(string, format!("# key_hex in a comment\ngpath = \"../remote/\"\ncommand_push = \"true\"\n\
+            command_pull = \"true\"\n\n[[keyring]]\nkey_hex = \"{}\"\nactive = false\n\n\
+            [[keyring]]\nid = \"{}\"\nkey_hex = \"{}\"\nactive = true\n",
+            slice_to_hex(&old), slice_to_hex(&key_id(&key)), "ab".repeat(L_KEY)));
+        assert_eq!(&*key_to_hex(&key), slice_to_hex(&key));
+        // data under the old key can still be read after the import of a new one
+        let blob = my_encrypt(b"content", &old, b"aad", Purpose::Blob, b"").unwrap();
+        let keyring = Config::load(&fname).unwrap().keyring(None, false).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(keyring.active(), &key);
+        assert_eq!(my_decrypt(&blob, &keyring, b"aad").unwrap(), b"content");
+    }
+
+    #[test]
+    fn write_key_only_touches_the_keyring() {
+        let fname = env::temp_dir().join(format!("symsync_test_keyring_{}.toml", std::process::id()));
+        let old = Key::from([7; L_KEY]);
+        fs::write(&fname, format!("gpath = \"../remote/\"\n\n[[keyring]]\nkey_hex = \"{}\"\nactive = true\n\n\
+            [other]\nactive = true\n", slice_to_hex(&old))).unwrap();
+        let new = Key::from([8; L_KEY]);
+        Config::write_key(&fname, &new).unwrap();
+        let string = fs::read_to_string(&fname).unwrap();
+        fs::remove_file(&fname).unwrap();
+        assert_eq!(string, format!("gpath = \"../remote/\"\n\n[[keyring]]\nkey_hex = \"{}\"\nactive = false\n\n\
+            [[keyring]]\nid = \"{}\"\nkey_hex = \"{}\"\nactive = true\n\n[other]\nactive = true\n",
+            slice_to_hex(&old), slice_to_hex(&key_id(&new)), slice_to_hex(&new)));
+    }
+
+    #[test]
+    fn wrong_key_is_reported_by_id() {
+        let blob = my_encrypt(b"content", &Key::from([7; L_KEY]), b"aad", Purpose::Blob, b"").unwrap();
+        assert!(my_decrypt(&blob, &Keyring::single(Key::from([8; L_KEY])), b"aad").unwrap_err().is::<KeyIdError>());
+    }
+
+    #[test]
+    fn keyring_picks_key_by_id() {
+        let old = Key::from([7; L_KEY]);
+        let new = Key::from([8; L_KEY]);
+        let keyring = Keyring { keys: vec![old.clone(), new.clone()], active: 1 };
+        let blob_old = my_encrypt(b"old", &old, b"aad", Purpose::Blob, b"").unwrap();
+        let blob_new = my_encrypt(b"new", keyring.active(), b"aad", Purpose::Blob, b"").unwrap();
+        assert_eq!(my_decrypt(&blob_old, &keyring, b"aad").unwrap(), b"old");
+        assert_eq!(my_decrypt(&blob_new, &keyring, b"aad").unwrap(), b"new");
+        assert_eq!(Header::from_bytes(&blob_new).unwrap().0.key_id, key_id(&new));
+        let err = my_decrypt(&blob_old, &Keyring::single(new.clone()), b"aad").unwrap_err();
+        assert!(err.is::<KeyIdError>());
+    }
+
+    #[test]
+    fn subkeys_are_independent() {
+        let key = Key::from([7; L_KEY]);
+        let image = derive_key(&key, Purpose::Image, b"");
+        assert_eq!(image, derive_key(&key, Purpose::Image, b""));
+        assert_ne!(image, derive_key(&key, Purpose::Blob, b""));
+        assert_ne!(derive_key(&key, Purpose::Blob, b"a"), derive_key(&key, Purpose::Blob, b"b"));
+        assert_ne!(image, key);
+        let blob = my_encrypt(b"content", &key, b"aad", Purpose::Blob, b"namehash").unwrap();
+        let (header, _) = Header::from_bytes(&blob).unwrap();
+        assert_eq!(header.subkey().unwrap(), Some((Purpose::Blob, &b"namehash"[..])));
+        assert_eq!(header.key_id, key_id(&key));
+    }
+
+    #[test]
+    fn update_naming_matches_new_entries() {
+        let keyring = Keyring::single(Key::from([7; L_KEY]));
+        let dir = test_dir("namehash");
+        let fname = dir.join("namehash.txt");
+        fs::write(&fname, "content").unwrap();
+        let mut image = Image::new();
+        image.siphashkey = Some((1, 2));
+        image.push(Image::metadata(&fname, image.blobname(&fname, &keyring).unwrap()).unwrap());
+        for naming in [Naming::Siphash, Naming::Siv] {
+            let mut remote = Image::new();
+            remote.siphashkey = Some((3, 4));
+            remote.naming = naming;
+            image.update_naming(&remote, &keyring).unwrap();
+            let fresh = Image::metadata(&fname, image.blobname(&fname, &keyring).unwrap()).unwrap();
+            assert_eq!(image.filesystem[0].namehash, fresh.namehash);
+        }
+        // the keys for the names are kept when another key is activated
+        let rotated = Keyring { keys: vec![Key::from([7; L_KEY]), Key::from([8; L_KEY])], active: 1 };
+        for naming in [Naming::Siphash, Naming::Siv] {
+            let mut image = Image::new();
+            image.naming = naming;
+            image.pin_namekey(&keyring);
+            let name = image.blobname(&fname, &keyring).unwrap();
+            image.pin_namekey(&rotated);
+            assert_eq!(image.blobname(&fname, &rotated).unwrap(), name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encrypted_names_roundtrip() {
+        let key = Key::from([7; L_KEY]);
+        let name = encrypt_name("./f1/g1/bytes.bin", &key).unwrap();
+        assert_eq!(name, encrypt_name("./f1/g1/bytes.bin", &key).unwrap());
+        assert_ne!(name, encrypt_name("./f1/g1/bytes.bim", &key).unwrap());
+        assert!(name.bytes().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()));
+        assert_eq!(decrypt_name(&name, &key).unwrap(), "./f1/g1/bytes.bin");
+        assert!(decrypt_name(&name, &Key::from([8; L_KEY])).unwrap_err().is::<SigError>());
+        let mut tampered = name.into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'a' {b'b'} else {b'a'};
+        assert!(decrypt_name(&String::from_utf8(tampered).unwrap(), &key).is_err());
+        // long paths are named by their synthetic IV only
+        let long = format!("./{}", "a".repeat(200));
+        let name = encrypt_name(&long, &key).unwrap();
+        assert!(name.len() <= L_NAME_MAX);
+        assert_eq!(name, encrypt_name(&long, &key).unwrap());
+        assert!(decrypt_name(&name, &key).is_err());
+    }
+
+    #[test]
+    fn header_without_subkey_uses_master_key() {
+        let key = Key::from([7; L_KEY]);
+        let nonce = gen_nonce();
+        let header = Header::new(Algorithm::Aes256Gcm, &key, &nonce).to_bytes();
+        let message = [&b"content"[..], &gen_obfuscation()].concat();
+        let mut tag = [0; L_TAG];
+        let aad = [&header[..], b"aad"].concat();
+        let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), &key, Some(&nonce), &aad, &message, &mut tag).unwrap();
+        let blob = [&header[..], &ciphertext, &tag].concat();
+        assert_eq!(my_decrypt(&blob, &Keyring::single(key.clone()), b"aad").unwrap(), b"content");
+    }
+
+    #[test]
+    fn mac_is_checked_before_decryption() {
+        let keyring = Keyring::single(Key::from([7; L_KEY]));
+        let dir = test_dir("mac");
+        let fname = dir.join("mac.txt");
+        fs::write(&fname, "content").unwrap();
+        let mut metadata = Image::metadata(&fname, "namehash".to_string()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let mut blob = my_encrypt(b"content", keyring.active(), &metadata.aad(), Purpose::Blob, b"").unwrap();
+        metadata.signature = slice_to_hex(&calc_mac(&blob, keyring.active()));
+        assert_eq!(open_blob(&blob, &metadata, &keyring, None).unwrap(), b"content");
+        let last = blob.len() - 1;
+        blob[last] ^= 1;
+        assert!(open_blob(&blob, &metadata, &keyring, None).unwrap_err().is::<SigError>());
+        blob[last] ^= 1;
+        metadata.signature = slice_to_hex(&calc_mac(&blob, &Key::from([8; L_KEY])));
+        assert!(open_blob(&blob, &metadata, &keyring, None).unwrap_err().is::<SigError>());
+    }
+
+    #[test]
+    fn generation_is_authenticated_with_the_image() {
+        let old: Image = serde_json::from_str(r#"{"last_update":0,"siphashkey":[1,2],"filesystem":[]}"#).unwrap();
+        assert_eq!(old.generation, 0);
+        let key = Key::from([7; L_KEY]);
+        let mut image = Image::new();
+        image.generation = 5;
+        let blob = my_encrypt(serde_json::to_string(&image).unwrap().as_bytes(), &key, IMAGE_REMOTE.as_bytes(), Purpose::Image, b"").unwrap();
+        let message = my_decrypt(&blob, &Keyring::single(key.clone()), IMAGE_REMOTE.as_bytes()).unwrap();
+        assert_eq!(serde_json::from_slice::<Image>(&message).unwrap().generation, 5);
+    }
+
+    #[test]
+    fn older_generation_is_refused() {
+        let path = env::temp_dir().join(format!("symsync_test_generation_{}", std::process::id()));
+        let mut image = Image::new();
+        image.generation = 5;
+        image.check_generation(&path).unwrap();
+        assert_eq!(Image::seen_generation(&path).unwrap(), 5);
+        image.generation = 4;
+        assert!(image.check_generation(&path).unwrap_err().is::<RollbackError>());
+        image.generation = 5;
+        image.check_generation(&path).unwrap();
+        image.generation = 6;
+        image.check_generation(&path).unwrap();
+        assert_eq!(Image::seen_generation(&path).unwrap(), 6);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn blob_can_be_recovered_without_image() {
+        let keyring = Keyring::single(Key::from([7; L_KEY]));
+        let dir = test_dir("recover");
+        let fname = dir.join("recover.txt");
+        fs::write(&fname, "content").unwrap();
+        let blobname = encrypt_name(fname.to_str().unwrap(), keyring.active()).unwrap();
+        let metadata = Image::metadata(&fname, blobname.clone()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let mut blob = Vec::new();
+        encrypt_blob(&b"content"[..], &mut blob, &metadata, keyring.active(), None, &FORMAT).unwrap();
+        let recovered = recover_blob(&blob, &blobname, &keyring).unwrap();
+        assert_eq!((&recovered.name, recovered.actually_modified), (&metadata.name, metadata.actually_modified));
+        assert_eq!(recovered.signature, slice_to_hex(&calc_mac(&blob, keyring.active())));
+        assert_eq!(open_blob(&blob, &recovered, &keyring, None).unwrap(), b"content");
+        assert!(recover_blob(&blob, "othername", &keyring).is_err());
+        let old = my_encrypt(b"content", keyring.active(), &metadata.aad(), Purpose::Blob, b"").unwrap();
+        assert!(recover_blob(&old, &blobname, &keyring).is_err());
+    }
+
+    #[test]
+    fn stream_roundtrip_and_tamper() {
+        let keyring = Keyring::single(Key::from([7; L_KEY]));
+        for l in [0, 1, L_CHUNK - 3, L_CHUNK, 3 * L_CHUNK + 5] {
+            let mut message = vec![0; l];
+            rand_bytes(&mut message).unwrap();
+            let mut blob = Vec::new();
+            encrypt_stream(&message[..], &mut blob, keyring.active(), b"aad", Purpose::Blob, b"", Vec::new()).unwrap();
+            assert_eq!(my_decrypt(&blob, &keyring, b"aad").unwrap(), message);
+            assert!(my_decrypt(&blob, &keyring, b"other aad").is_err());
+            let (_, l_header) = Header::from_bytes(&blob).unwrap();
+            if blob.len() - l_header > L_CHUNK + L_TAG {
+                // drop the last chunk
+                assert!(my_decrypt(&blob[..l_header + L_CHUNK + L_TAG], &keyring, b"aad").is_err());
+            }
+            if blob.len() - l_header > 2 * (L_CHUNK + L_TAG) {
+                // swap the first two chunks
+                let mut swapped = blob.clone();
+                swapped[l_header..l_header + 2 * (L_CHUNK + L_TAG)].rotate_left(L_CHUNK + L_TAG);
+                assert!(my_decrypt(&swapped, &keyring, b"aad").is_err());
+            }
+            let last = blob.len() - 1;
+            blob[last] ^= 1;
+            assert!(my_decrypt(&blob, &keyring, b"aad").unwrap_err().is::<SigError>());
+        }
+    }
+
+    #[test]
+    fn pieces_survive_an_insertion() {
+        let key = Key::from([7; L_KEY]);
+        // fixed pseudo-random content, such that the boundaries do not depend on the run
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut content: Vec<u8> = (0..2_000_000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 56) as u8
+        }).collect();
+        let split = |content: &[u8]| {
+            let mut chunker = Chunker::new(content, &key).unwrap();
+            let mut pieces = Vec::new();
+            while let Some(piece) = chunker.next_piece().unwrap() {
+                assert!(piece.len() <= L_PIECE_MAX);
+                pieces.push(piece_name(&piece, &key));
+            }
+            pieces
+        };
+        let before = split(&content);
+        assert!(before.len() > 4);
+        content.insert(1_000_000, 42);
+        let after = split(&content);
+        let kept = after.iter().filter(|name| before.contains(name)).count();
+        assert!(kept >= before.len() - 2);
+        assert!(split(b"").is_empty());
+    }
+
+    #[test]
+    fn piece_must_match_its_name() {
+        let keyring = Keyring::single(Key::from([7; L_KEY]));
+        let encrypt = |name: &str| {
+            let mut blob = Vec::new();
+            encrypt_stream(&b"piece"[..], &mut blob, keyring.active(), name.as_bytes(), Purpose::Piece, b"", Vec::new()).unwrap();
+            blob
+        };
+        let name = piece_name(b"piece", keyring.active());
+        assert_eq!(open_piece(&encrypt(&name), &name, &keyring).unwrap(), b"piece");
+        let other = piece_name(b"other", keyring.active());
+        assert!(open_piece(&encrypt(&name), &other, &keyring).is_err());
+        assert!(open_piece(&encrypt(&other), &other, &keyring).unwrap_err().is::<SigError>());
+    }
+
+    #[test]
+    fn identical_files_share_pieces() {
+        let key = Key::from([7; L_KEY]);
+        let gpath = test_dir("dedup");
+        let mut content = vec![0; 500_000];
+        rand_bytes(&mut content).unwrap();
+        let mut image = Image::new();
+        for namehash in ["blob1", "blob2"] {
+            let mut meta = test_metadata("./file", namehash);
+            write_pieces(&content[..], &BlobStore::new(&gpath, &[], &Keyring::single(key.clone())), &mut meta, &key, None, &FORMAT).unwrap();
+            image.filesystem.push(meta);
+        }
+        let pieces = image.filesystem[0].pieces.clone().unwrap();
+        assert_eq!(image.filesystem[1].pieces.as_ref(), Some(&pieces));
+        assert_eq!(get_filenames(&gpath).len(), pieces.len() + 2);
+        // a damaged piece is written again
+        writefile(&gpath.join(&pieces[0]), b"half written").unwrap();
+        let mut meta = test_metadata("./file", "blob3");
+        write_pieces(&content[..], &BlobStore::new(&gpath, &[], &Keyring::single(key.clone())), &mut meta, &key, None, &FORMAT).unwrap();
+        open_piece(&readfile(&gpath.join(&pieces[0])).unwrap(), &pieces[0], &Keyring::single(key.clone())).unwrap();
+        image.filesystem.remove(0);
+        assert!(image.referenced().contains(&pieces[0]));
+        image.filesystem.remove(0);
+        assert!(image.referenced().is_empty());
+        fs::remove_dir_all(&gpath).unwrap();
+    }
+
+    #[test]
+    fn text_is_compressed_before_encryption() {
+        let keyring = Keyring::single(Key::from([7; L_KEY]));
+        let gpath = test_dir("compression");
+        let content: Vec<u8> = (0..20_000).flat_map(|i| format!("line {}\n", i).into_bytes()).collect();
+        for (name, compressed) in [("notes.txt", true), ("photo.JPG", false)] {
+            let mut meta = test_metadata(name, "blob");
+            let format = BlobFormat { compression: Compression::Deflate, ..FORMAT };
+            write_pieces(&content[..], &BlobStore::new(&gpath, &[], &keyring), &mut meta, keyring.active(), None, &format).unwrap();
+            let mut opened = Vec::new();
+            let mut stored = 0;
+            for piece in meta.pieces.as_ref().unwrap() {
+                let blob = readfile(&gpath.join(piece)).unwrap();
+                let header = Header::from_bytes(&blob).unwrap().0;
+                assert_eq!(header.compression().unwrap() == Compression::Deflate, compressed);
+                stored += blob.len();
+                opened.extend(open_piece(&blob, piece, &keyring).unwrap());
+            }
+            assert_eq!(opened, content);
+            assert_eq!(stored < content.len() / 2, compressed);
+            fs::remove_dir_all(&gpath).unwrap();
+            fs::create_dir_all(&gpath).unwrap();
+        }
+        let mut noise = vec![0; 10_000];
+        rand_bytes(&mut noise).unwrap();
+        assert_eq!(compress(&noise, Compression::Deflate).unwrap(), None);
+        fs::remove_dir_all(&gpath).unwrap();
+    }
+
+    #[test]
+    fn small_blobs_are_packed() {
+        let keyring = Keyring::single(Key::from([7; L_KEY]));
+        let gpath = test_dir("packs");
+        let format = BlobFormat { pack_size: Some(1 << 16), decoys: 2, ..FORMAT };
+        let mut image = Image::new();
+        let mut contents = Vec::new();
+        for i in 0..12 {
+            let mut content = vec![0; 5000 + i];
+            rand_bytes(&mut content).unwrap();
+            let mut meta = test_metadata(&format!("file{}", i), &format!("blob{}", i));
+            let store = BlobStore::new(&gpath, &image.packs, &keyring);
+            write_pieces(&content[..], &store, &mut meta, keyring.active(), None, &format).unwrap();
+            image.filesystem.push(meta);
+            contents.push(content);
+        }
+        image.pack(&gpath, &keyring, keyring.active(), None, &format).unwrap();
+        assert!(image.packs.len() >= 2);
+        assert_eq!(image.decoys.len(), 2);
+        for name in image.packs.iter().map(|pack| &pack.name).chain(&image.decoys) {
+            assert_eq!(fs::metadata(gpath.join(name)).unwrap().len(), 1 << 16);
+        }
+        // the files of the packed blobs are deleted by clean_remote
+        for name in image.packed() {
+            fs::remove_file(gpath.join(name)).unwrap();
+        }
+        let mut store = BlobStore::new(&gpath, &image.packs, &keyring);
+        for (meta, content) in image.filesystem.iter().zip(&contents) {
+            check_mac(&store.read(&meta.namehash).unwrap()[..], &meta.signature, &keyring).unwrap();
+            let mut opened = Vec::new();
+            for piece in meta.pieces.as_ref().unwrap() {
+                opened.extend(open_piece(&store.read(piece).unwrap(), piece, &keyring).unwrap());
+            }
+            assert_eq!(&opened, content);
+        }
+        // packs that are less than half full are repacked
+        image.filesystem.truncate(2);
+        image.pack(&gpath, &keyring, keyring.active(), None, &format).unwrap();
+        assert_eq!(image.packs.len(), 1);
+        assert_eq!(image.packed().len(), 4);
+        let mut store = BlobStore::new(&gpath, &image.packs, &keyring);
+        assert_eq!(store.header("blob1").unwrap().key_id, key_id(keyring.active()));
+        assert!(open_pack(&readfile(&gpath.join(&image.decoys[0])).unwrap(), &image.decoys[0], &keyring).unwrap().is_empty());
+        fs::remove_dir_all(&gpath).unwrap();
+    }
+
+    #[test]
+    fn padding_policies() {
+        let keyring = Keyring::single(Key::from([7; L_KEY]));
+        let padded_len = |blob: &[u8]| blob.len() - Header::from_bytes(blob).unwrap().1 - L_TAG;
+        for padding in [Padding::None, Padding::Exponential, Padding::Padme, Padding::Pow2, Padding::Block(1000)] {
+            for l in [0, 1, 5, 999, 100_000] {
+                let mut message = vec![0; l];
+                rand_bytes(&mut message).unwrap();
+                let extensions: Vec<_> = padding.extension().into_iter().collect();
+                let blob = my_encrypt_ext(&message, keyring.active(), b"aad", Purpose::Blob, b"", extensions.clone()).unwrap();
+                assert_eq!(my_decrypt(&blob, &keyring, b"aad").unwrap(), message);
+                assert_eq!(Header::from_bytes(&blob).unwrap().0.padding().unwrap(), padding);
+                let padded = padded_len(&blob);
+                match padding {
+                    Padding::None => assert_eq!(padded, l),
+                    Padding::Pow2 => assert!(padded.is_power_of_two() && padded >= l + L_PADDING_LEN),
+                    Padding::Block(b) => assert_eq!(padded % b as usize, 0),
+                    Padding::Padme => assert!(padded >= l + L_PADDING_LEN && padded <= (l + L_PADDING_LEN) * 9 / 8),
+                    Padding::Exponential => assert!(padded >= l + 3),
+                }
+                let mut blob = Vec::new();
+                encrypt_stream(&message[..], &mut blob, keyring.active(), b"aad", Purpose::Blob, b"", extensions).unwrap();
+                assert_eq!(my_decrypt(&blob, &keyring, b"aad").unwrap(), message);
+            }
+        }
+        assert_eq!(Padding::Padme.padded_len(1_000_000 - L_PADDING_LEN), 1_015_808);
+        let config: Config = toml::from_str("padding = { block = 4096 }\ngpath = \"\"\ncommand_push = \"\"\ncommand_pull = \"\"").unwrap();
+        assert_eq!(config.padding, Padding::Block(4096));
+    }
+
+    #[test]
+    fn local_image_can_be_encrypted() {
+        let keyring = Keyring::single(Key::from([7; L_KEY]));
+        let mut image = Image::new();
+        image.generation = 42;
+        let plain = image.to_local_bytes(None).unwrap();
+        let encrypted = image.to_local_bytes(Some(&keyring)).unwrap();
+        assert!(encrypted.starts_with(MAGIC));
+        assert_eq!(Image::from_local_bytes(&encrypted, Some(&keyring)).unwrap().generation, 42);
+        // a clear image from before is still read, an encrypted one needs the key
+        assert_eq!(Image::from_local_bytes(&plain, Some(&keyring)).unwrap().generation, 42);
+        assert!(Image::from_local_bytes(&encrypted, None).is_err());
+        assert!(Image::from_local_bytes(&encrypted, Some(&Keyring::single(Key::from([8; L_KEY])))).is_err());
+    }
+
+    #[test]
+    fn local_image_follows_an_imported_key() {
+        // after a rekey on another device, this device still has its local image under the old master key
+        let dir = test_dir("local_image");
+        let (config_file, image_file) = (dir.join("config.toml"), dir.join("image.json"));
+        let old = Key::from([7; L_KEY]);
+        fs::write(&config_file, format!("key_hex = \"{}\"\nlocal_image_key = \"master\"\ngpath = \"\"\n\
+            command_push = \"\"\ncommand_pull = \"\"\n", slice_to_hex(&old))).unwrap();
+        let mut image = Image::new();
+        image.generation = 42;
+        fs::write(&image_file, image.to_local_bytes(LocalKey::Master.keyring(&Keyring::single(old)).as_ref()).unwrap()).unwrap();
+        // the new key is imported and the next update loads the local image
+        let new = Key::from([8; L_KEY]);
+        Config::write_key(&config_file, &new).unwrap();
+        let config = Config::load(&config_file).unwrap();
+        let keyring = config.local_key().unwrap().keyring(&config.keyring(None, false).unwrap());
+        assert_eq!(Image::from_local(&image_file, keyring.as_ref()).unwrap().generation, 42);
+        // it is saved with the new key, such that the old one is no longer needed
+        let buf = readfile(&image_file).unwrap();
+        assert_eq!(Header::from_bytes(&buf).unwrap().0.key_id, key_id(&new));
+        assert_eq!(Image::from_local(&image_file, Some(&Keyring::single(new))).unwrap().generation, 42);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wrong_key_is_caught_by_key_check() {
+        let gpath = test_dir("key_check");
+        let key = Key::from([7; L_KEY]);
+        let extensions = vec![(EXT_KEY_CHECK, key_check(&key).to_vec())];
+        let image = my_encrypt_ext(b"{}", &key, IMAGE_REMOTE.as_bytes(), Purpose::Image, b"", extensions).unwrap();
+        writefile(&gpath.join(IMAGE_REMOTE), &image).unwrap();
+        assert!(Jambon::check_remote_key(&gpath, &Keyring::single(key.clone())).is_ok());
+        let err = Jambon::check_remote_key(&gpath, &Keyring::single(Key::from([8; L_KEY]))).unwrap_err();
+        assert!(err.is::<KeyIdError>());
+        assert_ne!(key_check(&key), key_check(&Key::from([8; L_KEY])));
+        assert_eq!(fingerprint(&key_check(&key)).len(), L_KEY_CHECK / 2 * 5 - 1);
+        fs::remove_dir_all(&gpath).unwrap();
+    }
+
+    #[test]
+    fn exported_key_roundtrip() {
+        let key = Key::from([0xab; L_KEY]);
+        let code = key_to_code(&key);
+        assert_eq!(code.split('-').count(), 14);
+        assert_eq!(code_to_key(&code).unwrap(), key);
+        // case, spaces and look-alike letters do not matter
+        let typed = code.to_lowercase().replace('-', " ").replace('1', "l").replace('0', "o");
+        assert_eq!(code_to_key(&typed).unwrap(), key);
+        let typo = code.replacen(&code[..1], if &code[..1] == "A" {"B"} else {"A"}, 1);
+        assert!(code_to_key(&typo).is_err());
+        assert!(code_to_key(&code[..code.len() - 5]).is_err());
+        assert!(code_to_key(&format!("{}0", &*code)).is_err());
+        assert!(Config::hex_to_key("12a6c2").is_err());
+        assert!(Config::hex_to_key(&"g".repeat(2 * L_KEY)).is_err());
+    }
+
+    #[test]
+    fn three_way_decisions() {
+        use Side::*;
+        let table = [
+            // no entry in the local image from the last sync
+            (false, Absent, Absent, Decision::Nothing),
+            (false, Changed, Absent, Decision::Push),        // created locally
+            (false, Absent, Changed, Decision::Pull),        // created remotely
+            (false, Changed, Changed, Decision::Conflict),   // created on both sides
+            // synced before
+            (true, Unchanged, Unchanged, Decision::Nothing),
+            (true, Changed, Unchanged, Decision::Push),      // modified locally
+            (true, Unchanged, Changed, Decision::Pull),      // modified remotely
+            (true, Changed, Changed, Decision::Conflict),    // modified on both sides
+            (true, Absent, Unchanged, Decision::DeleteRemote), // deleted locally
+            (true, Unchanged, Absent, Decision::DeleteLocal),  // deleted remotely
+            (true, Absent, Changed, Decision::Conflict),     // deleted locally, modified remotely
+            (true, Changed, Absent, Decision::Conflict),     // modified locally, deleted remotely
+            (true, Absent, Absent, Decision::Forget),        // deleted on both sides
+            // a tombstone in the remote image
+            (true, Unchanged, Deleted, Decision::DeleteLocal),
+            (true, Changed, Deleted, Decision::Conflict),    // modified locally, deleted remotely
+            (true, Absent, Deleted, Decision::Forget),
+            (false, Unchanged, Deleted, Decision::DeleteLocal), // never synced, same content as the deleted version
+            (false, Changed, Deleted, Decision::Push),       // created again
+            (false, Absent, Deleted, Decision::Nothing),
+        ];
+        for (base, local, remote, decision) in table {
+            assert_eq!(decide(base, local, remote), decision, "base {} local {:?} remote {:?}", base, local, remote);
+        }
+    }
+
+    #[test]
+    fn sides_are_compared_with_the_base() {
+        let dir = test_dir("sides");
+        let fname = dir.join("sides.txt");
+        fs::write(&fname, b"version 1").unwrap();
+        let mut base = Image::metadata(&fname, "blob".to_string()).unwrap();
+        base.hash = Some(hash_file(&fname).unwrap());
+        assert_eq!(Jambon::local_side(&fname, Some(&base)).unwrap().0, Side::Unchanged);
+        assert_eq!(Jambon::local_side(&fname, None).unwrap().0, Side::Changed);
+        assert_eq!(Jambon::local_side(Path::new("no such file"), Some(&base)).unwrap().0, Side::Absent);
+        // touched without edits: the stat differs, the hash does not
+        let f = File::options().write(true).open(&fname).unwrap();
+        f.set_modified(SystemTime::now() + std::time::Duration::from_secs(100)).unwrap();
+        let (side, stat) = Jambon::local_side(&fname, Some(&base)).unwrap();
+        assert_eq!(side, Side::Unchanged);
+        assert_ne!(stat, base.stat);
+        // edited within the same second, with the same size and the mtime restored
+        let mtime = fs::metadata(&fname).unwrap().modified().unwrap();
+        fs::write(&fname, b"version 2").unwrap();
+        File::options().write(true).open(&fname).unwrap().set_modified(mtime).unwrap();
+        assert_eq!(Jambon::local_side(&fname, Some(&base)).unwrap().0, Side::Changed);
+        // entries of older versions only have the modification time
+        let legacy = Metadata { hash: None, stat: None, ..Image::metadata(&fname, "blob".to_string()).unwrap() };
+        assert_eq!(Jambon::local_side(&fname, Some(&legacy)).unwrap().0, Side::Unchanged);
+        // a clock that is behind still counts as a change
+        let mut remote = base.clone();
+        remote.actually_modified -= 1000;
+        assert_eq!(Jambon::remote_side(Some(&remote), Some(&base)), Side::Changed);
+        assert_eq!(Jambon::remote_side(Some(&base), Some(&base)), Side::Unchanged);
+        assert_eq!(Jambon::remote_side(None, Some(&base)), Side::Absent);
+        // another device wrote a new version within the same second
+        let mut remote = base.clone();
+        remote.hash = Some("0".repeat(64));
+        assert_eq!(Jambon::remote_side(Some(&remote), Some(&base)), Side::Changed);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn conflict_policies_follow_the_rules() {
+        let config: Config = toml::from_str(r#"
+            conflict = "newest"
+            conflict_rules = [
+                { glob = "**/*.org", policy = { merge = "merge-org {local} {remote}" } },
+                { glob = "*.log", policy = "local" },
+            ]
+            gpath = ""
+            command_push = ""
+            command_pull = ""
+        "#).unwrap();
+        let policies = config.conflict_policies().unwrap();
+        assert_eq!(policies.for_file(Path::new("./notes/todo.org")), &ConflictPolicy::Merge("merge-org {local} {remote}".to_string()));
+        assert_eq!(policies.for_file(Path::new("./todo.org")), &ConflictPolicy::Merge("merge-org {local} {remote}".to_string()));
+        assert_eq!(policies.for_file(Path::new("./build.log")), &ConflictPolicy::Local);
+        // `*` does not match across folders
+        assert_eq!(policies.for_file(Path::new("./logs/build.log")), &ConflictPolicy::Newest);
+        let config: Config = toml::from_str("gpath = \"\"\ncommand_push = \"\"\ncommand_pull = \"\"").unwrap();
+        assert_eq!(config.conflict_policies().unwrap().for_file(Path::new("./a.txt")), &ConflictPolicy::KeepBoth);
+        assert_eq!(format_utc(UNIX_EPOCH + std::time::Duration::from_secs(1792315800)), "2026-10-18T09:30:00Z");
+    }
+
+    #[test]
+    fn version_vectors_are_ordered() {
+        let version = |pairs: &[(&str, u64)]| pairs.iter().map(|&(device, n)| (device.to_string(), n)).collect::<BTreeMap<_, _>>();
+        let base = version(&[("a", 2), ("b", 1)]);
+        assert_eq!(compare_versions(&base, &base), Some(Ordering::Equal));
+        assert_eq!(compare_versions(&version(&[("a", 2), ("b", 2)]), &base), Some(Ordering::Greater));
+        assert_eq!(compare_versions(&version(&[("a", 1), ("b", 1)]), &base), Some(Ordering::Less));
+        assert_eq!(compare_versions(&version(&[("a", 2), ("b", 1), ("c", 1)]), &base), Some(Ordering::Greater));
+        // pushed by b and c without seeing each other
+        assert_eq!(compare_versions(&version(&[("a", 2), ("c", 1)]), &base), None);
+        assert_eq!(compare_versions(&version(&[("a", 1), ("b", 2)]), &base), None);
+    }
+
+    #[test]
+    fn tombstones_are_dropped_once_seen_everywhere() {
+        let mut image = Image::new();
+        assert!(image.register("a"));
+        assert!(image.register("b"));
+        assert!(!image.register("a"));
+        image.tombstones.push(Tombstone { name: "./x".to_string(), hash: None, deleted_by: "a".to_string(),
+            deleted_at: 0, seen_by: Vec::new(), version: BTreeMap::new() });
+        assert!(image.register("a"));
+        assert!(image.tombstone(Path::new("./x")).is_some());
+        assert!(image.register("b"));
+        assert!(image.tombstones.is_empty());
+    }
+
+    #[test]
+    fn keys_are_not_printed() {
+        let hex = "12a6c2b20f32bfe22ad6f32a173ec0faf3773f4320c6a13da7c5a9a9f4a09144";
+        let string = format!("key_hex = \"{}\"\ngpath = \"\"\ncommand_push = \"\"\ncommand_pull = \"\"", hex);
+        let config: Config = toml::from_str(&string).unwrap();
+        assert!(!format!("{:?}", config).contains(hex));
+        let key = Config::hex_to_key(hex).unwrap();
+        assert!(!format!("{:?} {}", key, key).contains(&hex[..8]));
+        assert_eq!(format!("{}", key), slice_to_hex(&key_id(&key)));
+    }
+
+    #[test]
+    fn legacy_cbc_still_decrypts() {
+        let key = Key::from([7; L_KEY]);
+        let iv: Iv = [3; L_IV];
+        let message = b"written by an older version".to_vec();
+        let padded = [&message[..], &gen_obfuscation()].concat();
+        let ciphertext = openssl::symm::encrypt(Cipher::aes_256_cbc(), &key, Some(&iv), &padded).unwrap();
+        assert_eq!(my_decrypt_cbc(&ciphertext, &key, &iv).unwrap(), message);
+    }
+
     #[test]
     fn test1(){
         let p = Path::new("test.txt");
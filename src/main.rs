@@ -15,6 +15,16 @@ commands:
     blindpush:  encrypt and push everything, deleting what might have been in remote folder    
      
     update:     syncronize based on modification time of files. After an initial BlindPush or BlindPull, this should be the default command 
+
+    rekey:      re-encrypt everything in remote folder with a new key. The new key has to be copied to all other devices afterwards
+
+    recover:    rebuild the image in remote folder from the files in it, if the image was lost or damaged
+
+    key fingerprint:  show the fingerprints of the keys of this device and of the remote folder, to compare them between devices
+
+    key export: show the key as a code with a checksum, to be written down or typed in on another device
+
+    key import: read a code from `key export` and write the key into .sync/config.toml
 ";
 
 	let args: Vec<String> = env::args().collect();
@@ -24,11 +34,17 @@ commands:
 	}
 	let goal;
 	if &args[1] == "blindpush" {
-		goal = Goal::BlindPush;
+		goal = Some(Goal::BlindPush);
 	} else if &args[1] == "blindpull" {
-		goal = Goal::BlindPull;
+		goal = Some(Goal::BlindPull);
 	} else if &args[1] == "update" {
-		goal = Goal::Update;
+		goal = Some(Goal::Update);
+	} else if &args[1] == "rekey" {
+		goal = Some(Goal::Rekey);
+	} else if &args[1] == "recover" {
+		goal = Some(Goal::Recover);
+	} else if &args[1] == "key" && args.len() > 2 && ["fingerprint", "export", "import"].contains(&args[2].as_str()) {
+		goal = None;
 	} else {
 		println!("{}", usage_message);
 		return Ok(())
@@ -37,7 +53,7 @@ commands:
 
 	// cd to localdir
 	match env::var("MYSYNCPATH") {
-		Ok(home) => {env::set_current_dir(&home).expect(format!("cannot go to directory {:?}", &home).as_str())}
+		Ok(home) => {env::set_current_dir(&home).unwrap_or_else(|_| panic!("cannot go to directory {:?}", &home))}
 		Err(e) => {
 			println!("could not read system variable MYSYNCPATH: {}", e);
 			println!("Did you export MYSYNCPATH? This is the path to the folder that you want to sync.");
@@ -46,6 +62,13 @@ commands:
 	
 	let config = Config::load(Path::new(".sync/config.toml")).expect("could not load config file");
 
+	let goal = match goal {
+		Some(goal) => goal,
+		None if &args[2] == "export" => return config.export_key(),
+		None if &args[2] == "import" => return config.import_key(Path::new(".sync/config.toml")),
+		None => return config.print_fingerprints(),
+	};
+
 	let mut jambon = Jambon::start(config, &goal).expect("Jambon::start in main() returned errer");
 
 	match goal {
@@ -62,13 +85,14 @@ commands:
 		}
 		Goal::Update => {
 			let fnames = get_filenames(&PathBuf::from("."));
-			for fname in &fnames {
-				jambon.update(&fname).expect("jambon.update() in main() returned error");
-			}
-			jambon.load_missing().expect("jambon.load_missing() in main() returned error");
-			let fnames = get_filenames(&PathBuf::from("."));
-			jambon.clean_image(&fnames).expect("jambon.clean_image() in main() returned error");
-			
+			jambon.sync(&fnames).expect("jambon.sync() in main() returned error");
+
+		}
+		Goal::Rekey => {
+			jambon.rekey().expect("jambon.rekey() in main() returned error");
+		}
+		Goal::Recover => {
+			jambon.recover().expect("jambon.recover() in main() returned error");
 		}
 	}
 